@@ -0,0 +1,122 @@
+//! Capture of the encrypted TLS data passing through `ext`, for
+//! `set_capture`
+//!
+//! Each chunk of data is written to the capture writer as a frame:
+//!
+//! - 1 byte direction: 0 for outgoing (to `ext.wr`), 1 for incoming
+//!   (from `ext.rd`)
+//! - 8 bytes timestamp: microseconds since the UNIX epoch, big-endian
+//! - 4 bytes length, big-endian
+//! - The data
+//!
+//! Without the `capture` cargo feature this all compiles down to
+//! nothing.
+
+use crate::record::{HandshakeWatch, RecordLimit};
+use std::io::{IoSlice, Write};
+#[cfg(feature = "capture")]
+use std::time::SystemTime;
+
+/// Optional writer receiving a copy of the encrypted data
+#[derive(Default)]
+pub(crate) struct Capture {
+    #[cfg(feature = "capture")]
+    writer: Option<Box<dyn Write + Send>>,
+}
+
+impl Capture {
+    /// Start capturing to `writer`, replacing any previous writer
+    #[cfg(feature = "capture")]
+    pub(crate) fn set(&mut self, writer: Box<dyn Write + Send>) {
+        self.writer = Some(writer);
+    }
+
+    /// Write a frame for `data` if capturing.  Capture stops on the
+    /// first error from the writer, since it is diagnostic and
+    /// shouldn't break the connection.
+    pub(crate) fn record(&mut self, outgoing: bool, data: &[u8]) {
+        #[cfg(feature = "capture")]
+        if let Some(ref mut writer) = self.writer {
+            if data.is_empty() {
+                return;
+            }
+            let micros = SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_micros() as u64)
+                .unwrap_or(0);
+            let mut head = [0; 13];
+            head[0] = if outgoing { 0 } else { 1 };
+            head[1..9].copy_from_slice(&micros.to_be_bytes());
+            head[9..].copy_from_slice(&(data.len() as u32).to_be_bytes());
+            if writer
+                .write_all(&head)
+                .and_then(|_| writer.write_all(data))
+                .is_err()
+            {
+                self.writer = None;
+            }
+        }
+        #[cfg(not(feature = "capture"))]
+        let _ = (outgoing, data);
+    }
+}
+
+/// Rarely used per-connection state for watching and checking the
+/// encrypted data.  The wrappers keep this boxed, and only allocate
+/// it once one of these features is enabled, so that the common case
+/// of many plain connections doesn't pay for the space.
+#[derive(Default)]
+pub(crate) struct Extras {
+    pub capture: Capture,
+    pub watch: Option<HandshakeWatch>,
+    pub record_limit: Option<RecordLimit>,
+}
+
+impl Extras {
+    /// Pass data going in the given direction to the capture and the
+    /// watch
+    pub(crate) fn observe(&mut self, outgoing: bool, data: &[u8]) {
+        self.capture.record(outgoing, data);
+        if let Some(ref mut watch) = self.watch {
+            watch.observe(outgoing, data);
+        }
+    }
+}
+
+/// Writer that passes data on to `inner`, and passes whatever
+/// `inner` accepted as outgoing data to `extras` if present
+pub(crate) struct Tee<'a, W: Write> {
+    pub inner: W,
+    pub extras: Option<&'a mut Extras>,
+}
+
+impl<W: Write> Tee<'_, W> {
+    fn record(&mut self, data: &[u8]) {
+        if let Some(ref mut extras) = self.extras {
+            extras.observe(true, data);
+        }
+    }
+}
+
+impl<W: Write> Write for Tee<'_, W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        self.record(&buf[..count]);
+        Ok(count)
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        let count = self.inner.write_vectored(bufs)?;
+        let mut left = count;
+        for buf in bufs {
+            let len = left.min(buf.len());
+            self.record(&buf[..len]);
+            left -= len;
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
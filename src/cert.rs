@@ -0,0 +1,95 @@
+//! Minimal DER parsing to get the validity end date of a certificate,
+//! which `rustls-webpki` doesn't expose
+
+use rustls::pki_types::{CertificateDer, UnixTime};
+use std::time::Duration;
+
+/// Get the "notAfter" time of a certificate, or `None` if it can't
+/// be parsed
+pub(crate) fn not_after(cert: &CertificateDer<'_>) -> Option<UnixTime> {
+    let (_, cert, _) = element(cert)?; // Certificate
+    let (_, tbs, _) = element(cert)?; // TBSCertificate
+    let (tag, _, mut rest) = element(tbs)?;
+    if tag == 0xA0 {
+        // Skip the serial number that follows the explicit version
+        (_, _, rest) = element(rest)?;
+    }
+    let (_, _, rest) = element(rest)?; // Signature algorithm
+    let (_, _, rest) = element(rest)?; // Issuer
+    let (_, validity, _) = element(rest)?;
+    let (_, _, validity) = element(validity)?; // notBefore
+    let (tag, time, _) = element(validity)?;
+    parse_time(tag, time)
+}
+
+/// Split the DER element at the start of `data` into its tag, its
+/// contents and the data following it
+fn element(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&tag, data) = data.split_first()?;
+    let (&len0, mut data) = data.split_first()?;
+    let len = if len0 < 0x80 {
+        usize::from(len0)
+    } else {
+        let count = usize::from(len0 & 0x7F);
+        if count == 0 || count > 4 || data.len() < count {
+            return None;
+        }
+        let len = data[..count]
+            .iter()
+            .fold(0, |acc, &b| (acc << 8) | usize::from(b));
+        data = &data[count..];
+        len
+    };
+    if data.len() < len {
+        return None;
+    }
+    Some((tag, &data[..len], &data[len..]))
+}
+
+/// Parse a UTCTime (tag 0x17) or GeneralizedTime (tag 0x18) in the
+/// "Z" form that RFC 5280 requires
+fn parse_time(tag: u8, time: &[u8]) -> Option<UnixTime> {
+    fn digits(s: &[u8]) -> Option<u64> {
+        s.iter().try_fold(0, |acc, &b| {
+            b.is_ascii_digit().then(|| acc * 10 + u64::from(b - b'0'))
+        })
+    }
+    let (year, rest) = match tag {
+        0x17 => {
+            let yy = digits(time.get(..2)?)?;
+            (if yy >= 50 { 1900 + yy } else { 2000 + yy }, &time[2..])
+        }
+        0x18 => (digits(time.get(..4)?)?, &time[4..]),
+        _ => return None,
+    };
+    if rest.len() != 11 || rest[10] != b'Z' {
+        return None;
+    }
+    let month = digits(&rest[0..2])?;
+    let day = digits(&rest[2..4])?;
+    let hour = digits(&rest[4..6])?;
+    let minute = digits(&rest[6..8])?;
+    let second = digits(&rest[8..10])?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 {
+        return None;
+    }
+    let days = days_since_epoch(year, month, day)?;
+    let secs = days * 86400 + hour * 3600 + minute * 60 + second;
+    Some(UnixTime::since_unix_epoch(Duration::from_secs(secs)))
+}
+
+/// Days from 1970-01-01 to the given date in the Gregorian
+/// calendar, or `None` if before 1970.  This is Howard Hinnant's
+/// `days_from_civil` algorithm.
+fn days_since_epoch(year: u64, month: u64, day: u64) -> Option<u64> {
+    let year = if month <= 2 {
+        year.checked_sub(1)?
+    } else {
+        year
+    };
+    let era = year / 400;
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    (era * 146097 + day_of_era).checked_sub(719468)
+}
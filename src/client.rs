@@ -1,7 +1,18 @@
-use crate::TlsError;
-use pipebuf::{tripwire, PBufRdWr};
-use rustls::{pki_types::ServerName, ClientConfig, ClientConnection};
-use std::io::ErrorKind;
+use crate::capture::{Extras, Tee};
+use crate::record::{self, HandshakeWatch, RecordLimit};
+use crate::{
+    Clock, CloseAction, CloseReason, ConnectionDescriptor, CryptoSummary, Progress,
+    ShutdownProgress, Termination, TlsError,
+};
+use pipebuf::{tripwire, PBufRdWr, PBufWr};
+use rustls::client::danger::ServerCertVerifier;
+use rustls::client::{ClientSessionStore, Resumption};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
+use rustls::{
+    ClientConfig, ClientConnection, ProtocolVersion, RootCertStore, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
+use std::io::{BufRead, ErrorKind, Write};
 use std::sync::Arc;
 
 /// [`PipeBuf`] wrapper of [**Rustls**] [`ClientConnection`]
@@ -16,6 +27,40 @@ use std::sync::Arc;
 /// [**Rustls**]: https://crates.io/crates/rustls
 pub struct TlsClient {
     cc: Option<ClientConnection>,
+    unstarted_config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+    transport_failed: bool,
+    recent_errors: Vec<TlsError>,
+    outbound_termination: Option<Termination>,
+    close_reason: Option<CloseReason>,
+    started: bool,
+    read_closed: bool,
+    write_closed: bool,
+    single_record: bool,
+    draining: bool,
+    close_notify_sent: bool,
+    close_notify_received: bool,
+    prebuffered: Vec<u8>,
+    coalesce_max: u32,
+    coalesce_held: u32,
+    quota_remaining: Option<u64>,
+    close_transport: bool,
+    max_plaintext_buffered: usize,
+    alert_on_error: bool,
+    clean_close_action: CloseAction,
+    abort_action: CloseAction,
+    refresh_threshold: u64,
+    bytes_since_refresh: u64,
+    borrowed_len: usize,
+    plaintext_waiting: usize,
+    early_data_copy: Vec<u8>,
+    max_iterations: u32,
+    flight_sent: bool,
+    round_trips: u8,
+    would_block_count: u64,
+    extras: Option<Box<Extras>>,
+    paused: bool,
+    plaintext_held: bool,
+    allowed_versions: Option<Vec<ProtocolVersion>>,
 }
 
 impl TlsClient {
@@ -25,13 +70,236 @@ impl TlsClient {
     pub fn new(
         config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
     ) -> Result<Self, rustls::Error> {
-        let cc = if let Some((conf, name)) = config {
+        let cc = if let Some((conf, name)) = config.clone() {
             Some(ClientConnection::new(conf, name)?)
         } else {
             None
         };
 
-        Ok(Self { cc })
+        Ok(Self {
+            cc,
+            unstarted_config: config,
+            transport_failed: false,
+            recent_errors: Vec::new(),
+            outbound_termination: None,
+            close_reason: None,
+            started: false,
+            read_closed: false,
+            write_closed: false,
+            single_record: false,
+            draining: false,
+            close_notify_sent: false,
+            close_notify_received: false,
+            prebuffered: Vec::new(),
+            coalesce_max: 0,
+            coalesce_held: 0,
+            quota_remaining: None,
+            close_transport: true,
+            max_plaintext_buffered: usize::MAX,
+            alert_on_error: true,
+            clean_close_action: CloseAction::CloseNotify,
+            abort_action: CloseAction::Abort,
+            refresh_threshold: crate::DEFAULT_KEY_REFRESH_BYTES,
+            bytes_since_refresh: 0,
+            borrowed_len: 0,
+            plaintext_waiting: 0,
+            early_data_copy: Vec::new(),
+            max_iterations: crate::DEFAULT_MAX_ITERATIONS,
+            flight_sent: false,
+            round_trips: 0,
+            would_block_count: 0,
+            extras: None,
+            paused: false,
+            plaintext_held: false,
+            allowed_versions: None,
+        })
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but with some
+    /// bytes that have already been read from the transport, for
+    /// example when upgrading a connection STARTTLS-style after
+    /// protocol sniffing.  These bytes are treated as coming before
+    /// anything that arrives in `ext.rd`.
+    pub fn new_with_prebuffered(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        prebuffered: &[u8],
+    ) -> Result<Self, rustls::Error> {
+        let mut this = Self::new(config)?;
+        this.prebuffered = prebuffered.to_vec();
+        Ok(this)
+    }
+
+    /// Switch a wrapper created without a configuration over to TLS,
+    /// STARTTLS-style.  Calls to [`TlsClient::process`] after this perform
+    /// the TLS handshake and then encrypt and decrypt data as normal.
+    /// Passthrough mode holds no data internally, so nothing is lost
+    /// at the switch-over, but the caller must make sure that the
+    /// plain-text protocol exchange has completed before switching,
+    /// i.e. that no more plain-text is expected from the peer.
+    ///
+    /// Fails if TLS is already active, or if Rustls rejects the
+    /// configuration.
+    pub fn activate_tls(
+        &mut self,
+        config: (Arc<ClientConfig>, ServerName<'static>),
+    ) -> Result<(), rustls::Error> {
+        if self.cc.is_some() {
+            return Err(rustls::Error::General("TLS is already active".into()));
+        }
+        let (conf, name) = config.clone();
+        self.cc = Some(ClientConnection::new(conf, name)?);
+        self.unstarted_config = Some(config);
+        Ok(())
+    }
+
+    /// Check that a configuration is accepted by Rustls, by doing a
+    /// trial construction of a connection and then discarding it.
+    /// This allows configurations to be checked at startup rather
+    /// than when the first connection is made.
+    pub fn validate_config(
+        config: (Arc<ClientConfig>, ServerName<'static>),
+    ) -> Result<(), rustls::Error> {
+        let (conf, name) = config;
+        ClientConnection::new(conf, name).map(|_| ())
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but with Rustls
+    /// getting the current time from `clock`, for example to make
+    /// certificate validity and ticket ages deterministic in tests.
+    /// This clones the configuration to replace its `time_provider`,
+    /// so for many connections it is cheaper to set the
+    /// `time_provider` on a shared configuration instead.
+    pub fn new_with_clock(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        clock: fn() -> UnixTime,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|(conf, name)| {
+            let mut conf = (*conf).clone();
+            conf.time_provider = Arc::new(Clock(clock));
+            (Arc::new(conf), name)
+        }))
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but only
+    /// accepting the given protocol versions for this connection,
+    /// e.g. to require TLS 1.3 when sharing a configuration with
+    /// connections that allow TLS 1.2.  Rustls doesn't allow the
+    /// versions of an existing configuration to be changed or
+    /// rebuilt, so the ClientHello still offers all the versions that
+    /// the configuration enables.  But as soon as the server picks a
+    /// version that isn't in `versions`, `ext.wr` is aborted and
+    /// [`TlsClient::process`] fails, before any application data is
+    /// exchanged.
+    pub fn new_with_versions(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<Self, rustls::Error> {
+        let mut this = Self::new(config)?;
+        this.allowed_versions = Some(versions.iter().map(|v| v.version).collect());
+        Ok(this)
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but with
+    /// session resumption disabled, so that every connection does a
+    /// full handshake, e.g. for high-security clients.  This clones
+    /// the configuration to replace its `resumption` setting, so for
+    /// many connections it is cheaper to set
+    /// `Resumption::disabled()` on a shared configuration instead.
+    pub fn new_without_resumption(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|(conf, name)| {
+            let mut conf = (*conf).clone();
+            conf.resumption = Resumption::disabled();
+            (Arc::new(conf), name)
+        }))
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but keeping
+    /// resumption data in `store` instead of the configuration's
+    /// session store, e.g. to share sessions through a distributed
+    /// cache.  This clones the configuration to replace its
+    /// `resumption` setting, so for many connections it is cheaper
+    /// to set `Resumption::store` on a shared configuration instead.
+    pub fn new_with_session_store(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        store: Arc<dyn ClientSessionStore>,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|(conf, name)| {
+            let mut conf = (*conf).clone();
+            conf.resumption = Resumption::store(store);
+            (Arc::new(conf), name)
+        }))
+    }
+
+    /// Create a new TLS engine for connecting to `name`, trusting the
+    /// root certificates from the platform's trust store, and using
+    /// the process-default crypto provider.  Fails if no provider
+    /// has been installed, e.g. with `CryptoProvider::install_default`,
+    /// or if no usable root certificates could be loaded.  Requires
+    /// the `native-roots` cargo feature.
+    #[cfg(feature = "native-roots")]
+    pub fn new_native_roots(name: ServerName<'static>) -> Result<Self, rustls::Error> {
+        Self::new(Some((crate::native_roots_config()?, name)))
+    }
+
+    /// Create a new TLS engine for connecting to `name` with mutual
+    /// TLS, trusting the root certificates in `roots` and presenting
+    /// the certificate chain `client_cert` with its private key
+    /// `client_key` if the server asks for a client certificate.
+    /// This uses the process-default crypto provider.  Fails if no
+    /// provider has been installed, e.g. with
+    /// `CryptoProvider::install_default`, or if Rustls rejects the
+    /// key.
+    pub fn new_mtls(
+        roots: RootCertStore,
+        client_cert: Vec<CertificateDer<'static>>,
+        client_key: PrivateKeyDer<'static>,
+        name: ServerName<'static>,
+    ) -> Result<Self, rustls::Error> {
+        let config = ClientConfig::builder_with_provider(crate::default_provider()?)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(client_cert, client_key)?;
+        Self::new(Some((Arc::new(config), name)))
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but offering
+    /// the given ALPN protocols for this connection instead of the
+    /// configuration's `alpn_protocols` list.  This clones the
+    /// configuration to make the change.
+    pub fn new_with_alpn_protocols(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        protocols: Vec<Vec<u8>>,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|(conf, name)| {
+            let mut conf = (*conf).clone();
+            conf.alpn_protocols = protocols;
+            (Arc::new(conf), name)
+        }))
+    }
+
+    /// Replace the server certificate verifier, for example for
+    /// trust-on-first-use flows.  The connection is rebuilt from a
+    /// copy of the configuration with `verifier` installed.  This is
+    /// only possible before the first call to
+    /// [`TlsClient::process`] after TLS was enabled, since after that
+    /// the handshake has started.
+    /// Fails if TLS is disabled, if the handshake has started, or if
+    /// Rustls rejects the new configuration.
+    pub fn reset_verifier(
+        &mut self,
+        verifier: Arc<dyn ServerCertVerifier>,
+    ) -> Result<(), rustls::Error> {
+        let (conf, name) = self.unstarted_config.clone().ok_or_else(|| {
+            rustls::Error::General("Verifier can only be reset before the handshake starts".into())
+        })?;
+        let mut conf = (*conf).clone();
+        conf.dangerous().set_certificate_verifier(verifier);
+        let conf = Arc::new(conf);
+        self.cc = Some(ClientConnection::new(conf.clone(), name.clone())?);
+        self.unstarted_config = Some((conf, name));
+        Ok(())
     }
 
     /// Get immutable access to the wrapped `ClientConnection`, if
@@ -40,6 +308,755 @@ impl TlsClient {
         self.cc.as_ref()
     }
 
+    /// Get mutable access to the wrapped `ClientConnection`, if
+    /// available, e.g. to call `send_close_notify` or use the
+    /// `writer` or `reader` directly.  Take care, since anything
+    /// done to the connection behind the wrapper's back may upset
+    /// [`TlsClient::process`].  Any chunk returned by
+    /// [`TlsClient::process_read_borrowed`] counts as consumed.
+    pub fn connection_mut(&mut self) -> Option<&mut ClientConnection> {
+        self.release_borrowed();
+        self.cc.as_mut()
+    }
+
+    /// Indicate that the transport has failed, e.g. a read or write
+    /// on the underlying socket returned an `io::Error`.  Aborts
+    /// `ext.wr` immediately, and on the next call to
+    /// [`TlsClient::process`] any pending data is discarded and `int.wr`
+    /// is aborted, so that the internal handlers see an "Aborting"
+    /// end-of-file.  The wrapper passes no more data after this call.
+    pub fn transport_error(&mut self, mut ext: PBufRdWr) {
+        self.transport_failed = true;
+        self.close_reason.get_or_insert(CloseReason::TransportError);
+        if !ext.wr.is_eof() {
+            ext.wr.abort();
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        }
+    }
+
+    /// Enable or disable single-record mode.  When enabled, at most
+    /// one TLS record's worth of plain-text is in flight at a time in
+    /// each direction, and each record is pushed onward as soon as it
+    /// is available (`ext.wr` for outgoing, `int.wr` for incoming).
+    /// Outgoing plain-text is passed to Rustls one record at a time,
+    /// and incoming plain-text is passed to `int.wr` one record at a
+    /// time, each only once the previous one has been consumed.  This
+    /// keeps latency to a minimum for interactive protocols at the
+    /// cost of some throughput for bulk transfers.
+    pub fn set_single_record_in_flight(&mut self, enabled: bool) {
+        self.single_record = enabled;
+    }
+
+    /// Get the IANA name of the negotiated cipher suite, for example
+    /// `"TLS13_AES_128_GCM_SHA256"`, suitable for use as a metrics
+    /// label.  Returns `None` if TLS is disabled, or if the cipher
+    /// suite has not been negotiated yet.
+    pub fn cipher_suite_name(&self) -> Option<&'static str> {
+        self.cc
+            .as_ref()
+            .and_then(|cc| cc.negotiated_cipher_suite())
+            .and_then(|suite| suite.suite().as_str())
+    }
+
+    /// Begin draining the connection.  Subsequent calls to
+    /// [`TlsClient::process`] ignore any new data in `int.rd`,
+    /// leaving it for the caller to handle, but still flush out any
+    /// plain-text already accepted by Rustls, and then close the TLS
+    /// stream cleanly with a `close_notify`.  The incoming direction
+    /// is not affected.
+    pub fn begin_drain(&mut self) {
+        self.draining = true;
+    }
+
+    /// Get the negotiated protocol version, e.g. for logging.
+    /// Returns `None` if TLS is disabled or the handshake has not
+    /// completed yet.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        let cc = self.cc.as_ref().filter(|cc| !cc.is_handshaking())?;
+        cc.protocol_version()
+    }
+
+    /// Get the negotiated cipher suite, e.g. for compliance checks.
+    /// Returns `None` if TLS is disabled or the handshake has not
+    /// completed yet.
+    pub fn negotiated_cipher_suite(&self) -> Option<SupportedCipherSuite> {
+        let cc = self.cc.as_ref().filter(|cc| !cc.is_handshaking())?;
+        cc.negotiated_cipher_suite()
+    }
+
+    /// Check whether the TLS handshake has completed.  Returns
+    /// `false` if TLS is disabled.  To act exactly once on
+    /// completion, see [`TlsClient::process_report`].
+    pub fn handshake_complete(&self) -> bool {
+        self.cc.as_ref().is_some_and(|cc| !cc.is_handshaking())
+    }
+
+    /// Check whether TLS 1.3 was negotiated.  Returns `false` if TLS
+    /// is disabled or the version has not been negotiated yet.
+    pub fn is_tls13(&self) -> bool {
+        self.cc.as_ref().and_then(|cc| cc.protocol_version()) == Some(ProtocolVersion::TLSv1_3)
+    }
+
+    /// Set the maximum length of the payload of an incoming TLS
+    /// record.  Record headers are checked as data is passed from
+    /// `ext.rd` to Rustls, and [`TlsClient::process`] fails if a peer
+    /// sends a longer record.  This limits the memory that a peer can
+    /// make us commit when only small records are expected.  Note
+    /// that the payload includes TLS overheads such as the AEAD tag,
+    /// and that the handshake must fit within the limit too.
+    ///
+    /// Record headers can only be found by following the stream from
+    /// the start, so this must be called before [`TlsClient::process`]
+    /// has done anything, and fails otherwise.
+    pub fn set_max_accepted_record_size(&mut self, bytes: usize) -> Result<(), TlsError> {
+        if self.started {
+            return Err(TlsError::Usage(
+                "Record size limit must be set before processing starts".into(),
+            ));
+        }
+        self.extras().record_limit = Some(RecordLimit::new(bytes));
+        Ok(())
+    }
+
+    /// Get a 32-byte value bound to the handshake transcript, for use
+    /// in channel-binding schemes.  Rustls doesn't expose the raw
+    /// transcript hash, so this is the RFC 9266 `tls-exporter`
+    /// channel binding: 32 bytes of exported keying material with
+    /// label `EXPORTER-Channel-Binding` and no context.  The exporter
+    /// secret is derived from the handshake transcript, so both ends
+    /// get the same value only if they saw the same handshake.
+    ///
+    /// Returns `None` if TLS is disabled, the handshake is not yet
+    /// complete, or the connection is not TLS 1.3, since RFC 9266
+    /// only defines this for TLS 1.3.
+    pub fn transcript_hash(&self) -> Option<Vec<u8>> {
+        let cc = self.cc.as_ref()?;
+        if cc.is_handshaking() || !self.is_tls13() {
+            return None;
+        }
+        cc.export_keying_material(vec![0; 32], b"EXPORTER-Channel-Binding", None)
+            .ok()
+    }
+
+    /// Fill `out` with keying material derived from the connection
+    /// secrets, as described in RFC 5705, using `label` and optional
+    /// `context`, e.g. for token binding.  Unlike calling Rustls
+    /// directly, invalid arguments are rejected with a descriptive
+    /// error: the handshake must be complete, `out` must not be empty
+    /// or exceed the TLS 1.3 limit of 255 hash blocks, and `label`
+    /// must not be empty.  Fails if TLS is disabled.  (buffered only)
+    pub fn export_keying_material(
+        &self,
+        out: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), TlsError> {
+        let cc = self.cc.as_ref().ok_or_else(|| {
+            TlsError::Usage("Can't export keying material: TLS is disabled".into())
+        })?;
+        crate::check_export(cc, out.len(), label)?;
+        cc.export_keying_material(out, label, context)
+            .map_err(|e| TlsError::rustls("Failed to export keying material", e))?;
+        Ok(())
+    }
+
+    /// Indicate that the transport has seen the peer vanish without a
+    /// clean shutdown, e.g. a connection reset.  Unlike
+    /// [`TlsClient::transport_error`], this takes effect immediately:
+    /// pending data is discarded and both `ext.wr` and `int.wr` are
+    /// aborted, so that the internal handlers see an "Aborting"
+    /// end-of-file straight away.  The wrapper passes no more data
+    /// after this call.
+    pub fn notify_peer_reset(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) {
+        self.transport_failed = true;
+        self.close_reason.get_or_insert(CloseReason::RemoteReset);
+        self.read_closed = true;
+        self.write_closed = true;
+        crate::abort_all(&mut ext, &mut int);
+        self.outbound_termination.get_or_insert(Termination::Abort);
+    }
+
+    /// Check whether the incoming plain-text stream has ended, i.e.
+    /// whether `int.wr` has been closed or aborted
+    pub fn read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Check whether the outgoing plain-text stream has ended, i.e.
+    /// whether end-of-file from `int.rd` has been handled, or `ext.wr`
+    /// has been closed or aborted.  This together with
+    /// [`TlsClient::read_closed`] makes half-closed states observable.
+    pub fn write_closed(&self) -> bool {
+        self.write_closed
+    }
+
+    /// Check whether the transport can be half-closed, e.g. with
+    /// `shutdown(Write)` on a TCP socket.  This is `true` once our
+    /// `close_notify` has been flushed to `ext.wr` whilst incoming
+    /// data is still expected, i.e. before the peer has closed its
+    /// side.
+    pub fn wants_transport_half_close(&self) -> bool {
+        self.close_notify_sent
+            && !self.cc.as_ref().is_some_and(|cc| cc.wants_write())
+            && !self.read_closed
+            && !self.close_notify_received
+    }
+
+    /// Get the negotiated ALPN protocol.  Returns `None` if TLS is
+    /// disabled, if the handshake has not got far enough yet, or if
+    /// no protocol was agreed.  The result is only meaningful once
+    /// the handshake is complete, i.e. once `is_handshaking()` on the
+    /// connection returns `false`, so a `None` before then doesn't
+    /// mean that no protocol will be agreed.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.cc.as_ref().and_then(|cc| cc.alpn_protocol())
+    }
+
+    /// Get the name of the negotiated key exchange group, for example
+    /// `"X25519"` or `"X25519MLKEM768"`, suitable for use as a
+    /// metrics label.  Returns `None` if TLS is disabled, or if key
+    /// exchange has not completed yet.
+    pub fn key_exchange_group_str(&self) -> Option<&'static str> {
+        self.cc
+            .as_ref()
+            .and_then(|cc| cc.negotiated_key_exchange_group())
+            .and_then(|group| group.name().as_str())
+    }
+
+    /// Get a summary of the negotiated cryptographic parameters, e.g.
+    /// for a compliance report.  Returns `None` if TLS is disabled or
+    /// the handshake has not completed yet.
+    pub fn crypto_summary(&self) -> Option<CryptoSummary> {
+        let cc = self.cc.as_ref()?;
+        if cc.is_handshaking() {
+            return None;
+        }
+        Some(CryptoSummary {
+            version: cc.protocol_version()?,
+            cipher_suite: cc.negotiated_cipher_suite()?.suite(),
+            kx_group: cc.negotiated_key_exchange_group().map(|group| group.name()),
+        })
+    }
+
+    /// Get the most recent errors returned by [`TlsClient::process`],
+    /// oldest first, for diagnostics when one failure leads to
+    /// others.  A failure to send the alert for an error is kept too,
+    /// just before the error itself.  Only the last few errors are
+    /// kept.
+    pub fn recent_errors(&self) -> &[TlsError] {
+        &self.recent_errors
+    }
+
+    /// Check how this wrapper ended the outgoing stream on `ext.wr`,
+    /// e.g. to decide whether to shut down a TCP connection with a
+    /// FIN or to reset it.  Returns `None` if `ext.wr` has not been
+    /// closed or aborted by the wrapper yet.
+    pub fn outbound_termination(&self) -> Option<Termination> {
+        self.outbound_termination
+    }
+
+    /// Get the reason the connection was ended by the transport, if
+    /// [`TlsClient::transport_error`] or [`TlsClient::notify_peer_reset`] has
+    /// been called.  The first reason reported is kept.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    /// Get the certificate chain presented by the peer, end-entity
+    /// certificate first, e.g. for authorization decisions.  Returns
+    /// `None` if TLS is disabled or if the peer hasn't presented a
+    /// certificate (yet).
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.cc.as_ref()?.peer_certificates()
+    }
+
+    /// Get the end of the validity period ("notAfter") of the
+    /// peer's end-entity certificate, e.g. to monitor for
+    /// certificates that will soon expire.  Returns `None` if TLS is
+    /// disabled, if the peer hasn't presented a certificate (yet),
+    /// or if it can't be parsed.
+    pub fn peer_cert_not_after(&self) -> Option<UnixTime> {
+        let cert = self.cc.as_ref()?.peer_certificates()?.first()?;
+        crate::cert::not_after(cert)
+    }
+
+    /// Get a summary of this wrapper's mode and negotiated
+    /// parameters, for logging or serialization
+    pub fn descriptor(&self) -> ConnectionDescriptor {
+        let cc = self.cc.as_ref();
+        ConnectionDescriptor {
+            server: false,
+            tls: cc.is_some(),
+            protocol_version: cc
+                .and_then(|cc| cc.protocol_version())
+                .and_then(|version| version.as_str())
+                .map(String::from),
+            cipher_suite: self.cipher_suite_name().map(String::from),
+            alpn_protocol: cc.and_then(|cc| cc.alpn_protocol()).map(|p| p.to_vec()),
+        }
+    }
+
+    /// Abort the connection, but only after encrypting and writing
+    /// out to `ext.wr` all the plain-text already committed to
+    /// `int.rd`.  This does a final [`TlsClient::process`] call, and then
+    /// discards any remaining data and aborts both `ext.wr` and
+    /// `int.wr` as for [`TlsClient::notify_peer_reset`].  Returns `Ok(true)`
+    /// if all the plain-text was flushed before the abort, or
+    /// `Ok(false)` if some was lost, e.g. because the handshake had
+    /// not yet completed.
+    pub fn abort_after_flush(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<bool, TlsError> {
+        self.process(ext.reborrow(), int.reborrow())?;
+        let flushed = int.rd.is_empty()
+            && match self.cc {
+                Some(ref cc) => !cc.is_handshaking() && !cc.wants_write(),
+                None => true,
+            };
+        self.notify_peer_reset(ext, int);
+        Ok(flushed)
+    }
+
+    /// Check that the connection completed cleanly, for use after a
+    /// correctness-critical transfer.  Both directions must have been
+    /// closed, and with TLS enabled, a `close_notify` must have been
+    /// both sent and received, and Rustls must have no TLS data left
+    /// waiting to be written to `ext.wr`.  Returns an error
+    /// describing the first problem found.
+    pub fn verify_complete(&self) -> Result<(), TlsError> {
+        if !self.read_closed || !self.write_closed {
+            return Err(TlsError::Check(
+                "Connection has not been closed in both directions".into(),
+            ));
+        }
+        if let Some(ref cc) = self.cc {
+            if cc.wants_write() {
+                return Err(TlsError::Check(
+                    "TLS data is still waiting to be written to ext.wr".into(),
+                ));
+            }
+            if !self.close_notify_sent {
+                return Err(TlsError::Check(
+                    "Outgoing TLS stream was not closed with close_notify".into(),
+                ));
+            }
+            if !self.close_notify_received {
+                return Err(TlsError::Check(
+                    "Incoming TLS stream was not closed with close_notify".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the DNS names listed in the subjectAltName extension of
+    /// the server's end-entity certificate, as accepted during the
+    /// handshake.  The certificate is parsed using `rustls-webpki`,
+    /// the same parser that Rustls uses to verify it.  Returns `None`
+    /// if TLS is disabled, if the server certificate hasn't been
+    /// received yet, or if it can't be parsed.
+    pub fn peer_cert_san(&self) -> Option<Vec<String>> {
+        let cert = self.cc.as_ref()?.peer_certificates()?.first()?;
+        crate::cert_dns_names(cert)
+    }
+
+    /// Send `buf` ahead of any plain-text still waiting in `int.rd`.
+    /// The data is passed straight to Rustls, and so goes out in the
+    /// next record written to `ext.wr` on the following call to
+    /// [`TlsClient::process`].  Records already encrypted by Rustls can't
+    /// be reordered, so this only jumps ahead of data that
+    /// [`TlsClient::process`] has not yet taken from `int.rd`.  If the
+    /// handshake is still in progress, the data is sent as soon as
+    /// it completes.  Returns the number of bytes accepted, which is
+    /// less than `buf.len()` only if a limit set with
+    /// [`TlsClient::set_buffer_limit`] has been reached, in which case the
+    /// rest should be passed again once `process` has written some
+    /// out.  Fails if TLS is disabled, or if a `close_notify` has
+    /// already been sent.
+    pub fn write_urgent(&mut self, buf: &[u8]) -> Result<usize, TlsError> {
+        if self.close_notify_sent {
+            return Err(TlsError::Usage(
+                "Urgent write attempted after close_notify was sent".into(),
+            ));
+        }
+        match self.cc {
+            Some(ref mut cc) => cc.writer().write(buf).map_err(|e| {
+                TlsError::Internal(format!(
+                    "Unexpected error from ClientConnection::writer.write: {e}"
+                ))
+            }),
+            None => Err(TlsError::Usage(
+                "Urgent write requires TLS to be active".into(),
+            )),
+        }
+    }
+
+    /// Queue `data` to be sent as TLS 1.3 0-RTT early data during
+    /// the handshake, on the next [`TlsClient::process`] call.  This
+    /// needs `enable_early_data` in the config and a resumed session
+    /// with a server that allows early data.  Returns the number of
+    /// bytes accepted, which is limited by the server's maximum, or
+    /// 0 if early data isn't available.  Note that early data may be
+    /// replayed by an attacker.  A copy is kept until the handshake
+    /// completes, and if the server rejected the early data, the
+    /// copy is sent again as normal data, ahead of anything new from
+    /// `int.rd`, so it arrives exactly once either way.  This can be
+    /// checked with `is_early_data_accepted` on the connection.
+    pub fn write_early_data(&mut self, data: &[u8]) -> Result<usize, TlsError> {
+        let count = match self.cc.as_mut().and_then(|cc| cc.early_data()) {
+            Some(mut early) => early.write(data).map_err(|e| {
+                TlsError::Internal(format!("Unexpected error from WriteEarlyData::write: {e}"))
+            })?,
+            None => return Ok(0),
+        };
+        self.early_data_copy.extend_from_slice(&data[..count]);
+        Ok(count)
+    }
+
+    /// Drive a clean shutdown of the connection.  Call this instead
+    /// of [`TlsClient::process`] once shutdown has been decided.  This
+    /// starts draining as for [`TlsClient::begin_drain`], so any new data
+    /// in `int.rd` is ignored, then processes as usual, reporting how
+    /// far the close has progressed.  Our `close_notify` is sent once
+    /// all accepted plain-text has been encrypted, and then incoming
+    /// data continues to be passed to `int.wr` until the peer closes
+    /// its side.  Once [`ShutdownProgress::Done`] is returned, the
+    /// transport can be closed.
+    ///
+    /// [`ShutdownProgress::Done`]: crate::ShutdownProgress::Done
+    pub fn drive_shutdown(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<ShutdownProgress, TlsError> {
+        self.draining = true;
+        self.process(ext.reborrow(), int.reborrow())?;
+        let flushed = ext.wr.is_eof()
+            || (self.close_notify_sent && !self.cc.as_ref().is_some_and(|cc| cc.wants_write()));
+        Ok(if !flushed {
+            ShutdownProgress::Flushing
+        } else if !self.read_closed && !self.close_notify_received {
+            ShutdownProgress::AwaitingPeerClose
+        } else {
+            ShutdownProgress::Done
+        })
+    }
+
+    /// Coalesce small writes into fewer TLS records, similar to
+    /// Nagle's algorithm.  When less than a full record of
+    /// plain-text is waiting in `int.rd`, it is left there for up to
+    /// `max_delay_iterations` calls to [`TlsClient::process`] to give the
+    /// protocol handler a chance to add more, reducing both overhead
+    /// and the information leaked by record sizes.  A call that holds
+    /// data back returns `Ok(true)`, so the usual loop calling
+    /// [`TlsClient::process`] until it reports no activity sends the data
+    /// once the delay has run out, without needing a timer.  A
+    /// pending end-of-file flushes immediately.  Set to 0 (the
+    /// default) to disable.
+    pub fn set_coalesce(&mut self, max_delay_iterations: u32) {
+        self.coalesce_max = max_delay_iterations;
+        self.coalesce_held = 0;
+    }
+
+    /// Limit the total plain-text that will be accepted from `int.rd`
+    /// and sent to the peer, counting from this call, e.g. for quota
+    /// enforcement.  Once `bytes` have been sent, the connection
+    /// starts draining as for [`TlsClient::begin_drain`]: anything more in
+    /// `int.rd` is left for the caller, and the outgoing TLS stream
+    /// is closed cleanly with a `close_notify`.  Incoming data is not
+    /// counted or limited.
+    pub fn set_plaintext_quota(&mut self, bytes: u64) {
+        self.quota_remaining = Some(bytes);
+        if bytes == 0 {
+            self.draining = true;
+        }
+    }
+
+    /// Control whether `ext.wr` is closed once the TLS stream has
+    /// ended cleanly.  When enabled (the default), `ext.wr` is
+    /// closed after the final `close_notify` has been written.  When
+    /// disabled, the `close_notify` is still sent, but `ext.wr` is
+    /// left open so that the caller can manage the transport, e.g.
+    /// for a multiplexed transport that outlives the TLS connection.
+    /// Aborts are still passed through to `ext.wr`.
+    pub fn set_manage_transport_close(&mut self, enabled: bool) {
+        self.close_transport = enabled;
+    }
+
+    /// Change what is sent to the peer when the internal side ends
+    /// `int.rd`, e.g. for a gateway translating between protocols
+    /// with different close semantics.  `clean_to` applies to a
+    /// clean close of `int.rd`, and `abort_to` to an abort.  The
+    /// default is [`CloseAction::CloseNotify`] for a clean close and
+    /// [`CloseAction::Abort`] for an abort.  This has no effect if TLS
+    /// is disabled, in which case the end of `int.rd` is passed
+    /// through unchanged.  (buffered only)
+    pub fn set_internal_close_mapping(&mut self, clean_to: CloseAction, abort_to: CloseAction) {
+        self.clean_close_action = clean_to;
+        self.abort_action = abort_to;
+    }
+
+    /// Control whether a fatal alert is sent to the peer when a TLS
+    /// error occurs.  When enabled (the default), the alert that
+    /// Rustls generates is written to `ext.wr` before the error is
+    /// returned, as for Rustls.  When disabled, no alert is sent and
+    /// `ext.wr` is aborted instead, so that the peer learns nothing
+    /// about the reason, e.g. to avoid fingerprinting.  (buffered
+    /// only)
+    pub fn set_send_alert_on_error(&mut self, enabled: bool) {
+        self.alert_on_error = enabled;
+    }
+
+    /// Set the limit on the plain-text and TLS data that Rustls will
+    /// buffer internally, as for Rustls `set_buffer_limit`.  If the
+    /// limit is reached, data is left in `int.rd` until Rustls has
+    /// space again.  Has no effect if the connection has not been
+    /// created yet, i.e. if TLS is disabled or a server is still
+    /// waiting for the ClientHello.
+    pub fn set_buffer_limit(&mut self, limit: Option<usize>) {
+        if let Some(ref mut cc) = self.cc {
+            cc.set_buffer_limit(limit);
+        }
+    }
+
+    /// Set a high-watermark for the decrypted plain-text waiting in
+    /// `int.wr`, e.g. to stop a fast sender from growing `int.wr`
+    /// without bound whilst the internal side is slow to consume it.
+    /// Once `int.wr` holds more than `bytes`, any more plain-text is
+    /// held in Rustls, and TLS data is left in `ext.rd`, until the
+    /// internal side has consumed enough.  No more than `bytes` is
+    /// passed on at a time, so `int.wr` never holds more than twice
+    /// `bytes`.  Held data doesn't count as activity, so a loop on
+    /// the return value of [`TlsClient::process`] stops rather than
+    /// growing `int.wr`.  The minimum is 1 byte.  The default is no
+    /// limit.  (buffered only)
+    pub fn set_max_plaintext_buffered(&mut self, bytes: usize) {
+        self.max_plaintext_buffered = bytes.max(1);
+    }
+
+    /// Remove the limit on the data that Rustls will buffer
+    /// internally, as for Rustls `set_buffer_limit(None)`, e.g. for
+    /// maximum throughput in bulk transfers.  Be aware that Rustls
+    /// will then accept any amount of plain-text before the
+    /// handshake completes, and any amount of TLS data that can't
+    /// be written out, so memory use is bounded only by what the
+    /// internal side sends.  As for [`TlsClient::set_buffer_limit`], this
+    /// has no effect if the connection has not been created yet.
+    pub fn set_unbounded_buffering(&mut self) {
+        self.set_buffer_limit(None);
+    }
+
+    /// Pause or resume the flow of application data, e.g. for flow
+    /// control at the application layer.  While paused,
+    /// [`TlsClient::process`] neither reads from `int.rd` nor writes to
+    /// `int.wr`, including end-of-file, but still advances the
+    /// handshake and handles incoming alerts, `close_notify` and key
+    /// updates.  Incoming plain-text is held in Rustls until resumed.
+    /// Once Rustls holds 16KiB, its fixed limit, no more TLS data is
+    /// read from `ext.rd` until resumed.  (buffered only)
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Set a callback to observe the handshake messages passing
+    /// through `ext` in either direction, e.g. for a monitoring proxy
+    /// that logs handshake metadata.  It is called with the message
+    /// type (e.g. 1 for ClientHello, 2 for ServerHello) and length
+    /// as each message header is seen in the TLS record framing.
+    /// Nothing is decrypted, so only handshake messages sent in the
+    /// clear are seen.  For TLS 1.2 that is everything up to the
+    /// ChangeCipherSpec, but TLS 1.3 encrypts everything after the
+    /// ServerHello.  Nothing is reported after a ChangeCipherSpec in
+    /// that direction, so with TLS 1.3 a ClientHello sent again after
+    /// a HelloRetryRequest may be missed.  (buffered only)
+    pub fn on_handshake_message(&mut self, callback: impl FnMut(u8, usize) + Send + 'static) {
+        self.extras().watch = Some(HandshakeWatch::new(Box::new(callback)));
+    }
+
+    /// Check whether a TLS 1.3 key update is recommended, because
+    /// the plain-text sent and received since the last call to
+    /// [`TlsClient::refresh_traffic_keys`] (or since the start) has
+    /// reached the threshold.  The default threshold is 1GiB, which
+    /// is well within the AEAD usage limits.  Rustls also refreshes
+    /// keys automatically when they near those limits, so this is a
+    /// more conservative policy on top of that.  Always `false` for
+    /// TLS 1.2 or if TLS is disabled.
+    pub fn should_refresh_keys(&self) -> bool {
+        self.is_tls13() && self.bytes_since_refresh >= self.refresh_threshold
+    }
+
+    /// Set the plain-text volume in bytes after which
+    /// [`TlsClient::should_refresh_keys`] returns `true`
+    pub fn set_key_refresh_threshold(&mut self, bytes: u64) {
+        self.refresh_threshold = bytes;
+    }
+
+    /// Send a TLS 1.3 `key_update` to refresh the traffic keys, as
+    /// for Rustls `refresh_traffic_keys`.  The peer is also asked to
+    /// update its keys.  The message goes out on the next call to
+    /// [`TlsClient::process`].  Fails if TLS is disabled, if the
+    /// handshake is not yet complete, or if TLS 1.2 was negotiated.
+    pub fn refresh_traffic_keys(&mut self) -> Result<(), rustls::Error> {
+        match self.cc {
+            Some(ref mut cc) => {
+                cc.refresh_traffic_keys()?;
+                self.bytes_since_refresh = 0;
+                Ok(())
+            }
+            None => Err(rustls::Error::General("TLS is not active".into())),
+        }
+    }
+
+    /// Limit the number of iterations of the internal loop within a
+    /// single call to [`TlsClient::process`], to guard against livelock
+    /// in adversarial conditions.  If the limit is reached, the call
+    /// returns `Ok(true)` with work remaining, so the caller regains
+    /// control and should call again later.  The default is 100000,
+    /// which is far more than normal use needs.
+    pub fn set_max_iterations(&mut self, max: u32) {
+        self.max_iterations = max;
+    }
+
+    /// Get the number of times that reading decrypted data from
+    /// Rustls found it temporarily empty (`WouldBlock`) since the
+    /// wrapper was created or the count was last reset, for
+    /// diagnosing throughput problems.  Normally this stays at zero.
+    /// (buffered only)
+    pub fn would_block_count(&self) -> u64 {
+        self.would_block_count
+    }
+
+    /// Get the number of bytes of decrypted plain-text waiting in
+    /// Rustls, not yet passed to `int.wr`.  This is normally zero
+    /// after [`TlsClient::process`], but plain-text is left in Rustls
+    /// whilst paused, beyond the
+    /// [`TlsClient::set_max_plaintext_buffered`] limit, in single-record
+    /// mode, or when read with [`TlsClient::process_read_borrowed`], in
+    /// which case the chunk last returned is not counted.  Returns 0
+    /// if TLS is not active, since data is then passed straight
+    /// through.  (buffered only)
+    pub fn available_plaintext(&self) -> usize {
+        if self.cc.is_some() {
+            self.plaintext_waiting
+        } else {
+            0
+        }
+    }
+
+    /// Reset the count returned by [`TlsClient::would_block_count`]
+    pub fn reset_would_block_count(&mut self) {
+        self.would_block_count = 0;
+    }
+
+    /// Get the number of round trips the handshake took, once it has
+    /// completed, e.g. for latency analysis.  This counts the times
+    /// that data arrived from the peer after a handshake flight had
+    /// been sent.  A normal TLS 1.3 handshake takes 1, and one with
+    /// a HelloRetryRequest takes 2.  A full TLS 1.2 handshake takes 2
+    /// as seen by the client, but 1 as seen by the server, since the
+    /// server sends the final flight.  Returns `None` if TLS is
+    /// disabled or the handshake is still in progress.
+    pub fn handshake_round_trips(&self) -> Option<u8> {
+        match self.cc {
+            Some(ref cc) if !cc.is_handshaking() => Some(self.round_trips),
+            _ => None,
+        }
+    }
+
+    /// Capture a copy of all the encrypted TLS data passing through
+    /// `ext` in both directions to `writer`, with timestamps, for
+    /// offline analysis.  Each chunk is written as a frame: 1 byte
+    /// direction (0 for outgoing, 1 for incoming), 8 bytes of
+    /// microseconds since the UNIX epoch and 4 bytes of length, both
+    /// big-endian, followed by the data.  Only ciphertext is
+    /// captured, so this is safe to share, or it can be combined
+    /// with a key log to decrypt it.  Capture stops if `writer`
+    /// returns an error.  Requires the `capture` cargo feature.
+    #[cfg(feature = "capture")]
+    pub fn set_capture(&mut self, writer: Box<dyn Write + Send>) {
+        self.extras().capture.set(writer);
+    }
+
+    /// Move all the decrypted plain-text still buffered in Rustls to
+    /// `int`, returning the number of bytes moved, e.g. at teardown
+    /// after the peer has closed, to make sure that nothing is lost.
+    /// Plain-text is normally moved to `int.wr` by
+    /// [`TlsClient::process`] as soon as it is decrypted, but it may be
+    /// left in Rustls after using [`TlsClient::process_read_borrowed`].
+    /// Any chunk previously returned by that call counts as consumed.
+    /// Fails if `int` has already been closed and there is data to
+    /// move.
+    pub fn drain_remaining(&mut self, int: PBufWr) -> Result<usize, TlsError> {
+        self.drain_plaintext(int, usize::MAX, usize::MAX)
+    }
+
+    /// Move plain-text from Rustls to `int` until `int` holds more
+    /// than `watermark` bytes, moving at most `limit` bytes at a time
+    fn drain_plaintext(
+        &mut self,
+        mut int: PBufWr,
+        watermark: usize,
+        limit: usize,
+    ) -> Result<usize, TlsError> {
+        self.release_borrowed();
+        let cc = match self.cc {
+            Some(ref mut cc) => cc,
+            None => return Ok(0),
+        };
+        let mut reader = cc.reader();
+        let mut total = 0;
+        while !int.exceeds_limit(watermark) {
+            let chunk = match reader.fill_buf() {
+                Ok(chunk) => chunk,
+                // No more data, and the peer has either not closed
+                // yet or closed without a `close_notify`
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::UnexpectedEof) => {
+                    break
+                }
+                Err(e) => return Err(TlsError::Decode(format!("TLS read error: {e}"))),
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            if int.is_eof() {
+                return Err(TlsError::Usage(
+                    "Can't drain plain-text: int.wr is closed".into(),
+                ));
+            }
+            let len = chunk.len().min(limit);
+            int.append(&chunk[..len]);
+            reader.consume(len);
+            self.plaintext_waiting = self.plaintext_waiting.saturating_sub(len);
+            total += len;
+        }
+        self.bytes_since_refresh += total as u64;
+        Ok(total)
+    }
+
+    /// Check whether application data written to `int.rd` can be sent
+    /// right away, i.e. whether the handshake is complete.  Early
+    /// data isn't taken from `int.rd`, so it isn't considered here.
+    /// Always `true` if TLS is disabled, and `false` once the
+    /// outgoing side has closed or the transport has failed.
+    pub fn can_send_now(&self) -> bool {
+        !self.write_closed
+            && !self.transport_failed
+            && !self.cc.as_ref().is_some_and(|cc| cc.is_handshaking())
+    }
+
+    /// Check whether the wrapper can be dropped without leaving the
+    /// peer hanging.  This is true if [`TlsClient::process`] has never
+    /// done anything, if the transport has failed, or if both
+    /// directions have ended and `ext` (the `ext.wr` passed to
+    /// [`TlsClient::process`]) has been closed or aborted.
+    pub fn is_safe_to_drop(&self, ext: &PBufWr) -> bool {
+        !self.started
+            || self.transport_failed
+            || (ext.is_eof() && self.read_closed && self.write_closed)
+    }
+
     /// Process as much data as possible, moving data between `ext`
     /// and `int`.  `ext` is the pipe which typically carries TLS
     /// protocol data to/from an external TCP connection.  `int` is
@@ -60,94 +1077,548 @@ impl TlsClient {
     /// results in an "Aborting" end-of-file.  Note that some TLS
     /// libraries always end their streams with an unclean shutdown.
     ///
+    /// If the internal side closes `int.wr` itself once the
+    /// handshake is complete, because it wants no more data, then no
+    /// more TLS data is read from `ext.rd`.  Anything further from
+    /// the peer is left unread there rather than being decrypted and
+    /// discarded.  To also close the connection towards the peer,
+    /// call [`TlsClient::begin_drain`].
+    ///
     /// Returns `Ok(true)` if there was activity, `Ok(false)` if no
     /// progress could be made, and `Err(_)` if there was an error.
     pub fn process(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) -> Result<bool, TlsError> {
-        let before = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
+        self.release_borrowed();
+        if self.cc.is_some() {
+            self.unstarted_config = None;
+        }
+        let result = self.process_inner(ext.reborrow(), int.reborrow());
+        match result {
+            Ok(activity) => {
+                self.started |= activity;
+                self.debug_check(&ext, &int);
+            }
+            Err(ref e) => crate::record_error(&mut self.recent_errors, e),
+        }
+        result
+    }
 
+    /// Make sure that everything waiting to be sent has been written
+    /// to `ext.wr` and pushed, e.g. before blocking on a long
+    /// upstream read, so that the peer isn't left waiting.  This does
+    /// a [`TlsClient::process`] call without holding back small writes
+    /// for [`TlsClient::set_coalesce`], and then writes out anything
+    /// still queued in Rustls.  Nothing is written if `ext.wr` has
+    /// been closed.
+    pub fn flush_before_block(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<(), TlsError> {
+        self.coalesce_held = self.coalesce_max;
+        self.process(ext.reborrow(), int.reborrow())?;
         if let Some(ref mut cc) = self.cc {
+            while cc.wants_write() && !ext.wr.is_eof() {
+                // Not expecting any error when writing to a PipeBuf
+                cc.write_tls(&mut Tee {
+                    inner: &mut ext.wr,
+                    extras: self.extras.as_deref_mut(),
+                })
+                .map_err(|e| {
+                    TlsError::Internal(format!(
+                        "Unexpected error from ClientConnection::write_tls: {e}"
+                    ))
+                })?;
+            }
+        }
+        if !ext.wr.is_eof() {
+            ext.wr.push();
+        }
+        Ok(())
+    }
+
+    /// Process as for [`TlsClient::process`], but retry any recoverable
+    /// failure a bounded number of times before returning an error.
+    /// The buffered wrapper has no recoverable failures, because
+    /// Rustls buffers internally and writes to a pipe always
+    /// succeed, and errors from Rustls itself (e.g. a failure to
+    /// decrypt) are always fatal.  So this is the same as
+    /// [`TlsClient::process`], and is provided so that code can use the
+    /// same call for both the buffered and unbuffered wrappers.
+    pub fn process_retry(&mut self, ext: PBufRdWr, int: PBufRdWr) -> Result<bool, TlsError> {
+        self.process(ext, int)
+    }
+
+    // Check that the wrapper's state and the pipe states agree after
+    // a successful `process`.  This compiles out in release builds.
+    fn debug_check(&self, ext: &PBufRdWr, int: &PBufRdWr) {
+        debug_assert!(
+            self.outbound_termination.is_none() || ext.wr.is_eof(),
+            "ext.wr termination recorded but ext.wr is still open"
+        );
+        debug_assert!(
+            !self.read_closed || int.wr.is_eof(),
+            "Read side marked closed but int.wr is still open"
+        );
+        debug_assert!(
+            !self.write_closed || int.rd.is_done() || ext.wr.is_eof(),
+            "Write side marked closed but neither int.rd nor ext.wr has ended"
+        );
+        debug_assert!(
+            self.cc.is_none()
+                || self.outbound_termination != Some(Termination::Close)
+                || self.close_notify_sent,
+            "ext.wr closed cleanly without sending close_notify"
+        );
+    }
+
+    /// Process incoming TLS data from `ext` and return a view of the
+    /// next chunk of decrypted plain-text directly from the Rustls
+    /// buffer, without copying it into a pipe.  This is for
+    /// zero-copy parsers.  Any handshake or other TLS data due to be
+    /// sent is written to `ext.wr`.  The returned chunk counts as
+    /// consumed on the next call to this method,
+    /// [`TlsClient::process`] or [`TlsClient::drain_remaining`], so it must be
+    /// used or copied before then.  The same checks and limits
+    /// apply as for `process`, and any failure is kept for
+    /// `recent_errors`.
+    ///
+    /// Returns `Ok(None)` if no plain-text is available yet, which
+    /// includes whilst paused, and `Ok(Some(&[]))` once the peer has
+    /// sent `close_notify` and all the data has been read.
+    /// End-of-file on `ext.rd` is not handled here, so call
+    /// [`TlsClient::process`] to pass that on.
+    /// Fails if TLS is not active.
+    pub fn process_read_borrowed(&mut self, mut ext: PBufRdWr) -> Result<Option<&[u8]>, TlsError> {
+        self.release_borrowed();
+        if self.cc.is_some() {
+            self.unstarted_config = None;
+        }
+        match self.read_borrowed_inner(ext.reborrow()) {
+            Ok(activity) => self.started |= activity,
+            Err(e) => {
+                crate::record_error(&mut self.recent_errors, &e);
+                return Err(e);
+            }
+        }
+        let cc = match self.cc {
+            // Plain-text is left in Rustls whilst paused
+            Some(ref mut cc) if !self.paused => cc,
+            _ => return Ok(None),
+        };
+        match cc.reader().into_first_chunk() {
+            Ok(chunk) => {
+                self.borrowed_len = chunk.len();
+                self.bytes_since_refresh += chunk.len() as u64;
+                self.plaintext_waiting = self.plaintext_waiting.saturating_sub(chunk.len());
+                Ok(Some(chunk))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => {
+                let e = TlsError::Decode(format!("TLS read error: {e}"));
+                crate::record_error(&mut self.recent_errors, &e);
+                Err(e)
+            }
+        }
+    }
+
+    // Body of `process_read_borrowed`, without the recording of
+    // errors: write out whatever is queued, and read from `ext.rd`
+    // until Rustls has plain-text to return or can do nothing more
+    fn read_borrowed_inner(&mut self, mut ext: PBufRdWr) -> Result<bool, TlsError> {
+        if self.cc.is_none() {
+            return Err(TlsError::Usage("TLS is not active".into()));
+        }
+        let before = tripwire!(ext.rd, ext.wr);
+        for _ in 0..self.max_iterations {
+            self.check_handshake(ext.reborrow())?;
+            if self.write_ext(ext.reborrow())? {
+                continue;
+            }
+            if self.paused || self.read_ext(ext.reborrow())?.is_none() {
+                break;
+            }
+        }
+        let after = tripwire!(ext.rd, ext.wr);
+        Ok(after != before)
+    }
+
+    // Once the handshake is complete, drop the copy of the early data
+    // if the server accepted it, or else queue it again as normal
+    // data.  Returns `true` if anything was queued.
+    fn resend_early_data(&mut self) -> Result<bool, TlsError> {
+        let Some(ref mut cc) = self.cc else {
+            return Ok(false);
+        };
+        if self.early_data_copy.is_empty() || cc.is_handshaking() {
+            return Ok(false);
+        }
+        if cc.is_early_data_accepted() {
+            self.early_data_copy = Vec::new();
+            return Ok(false);
+        }
+        // Rustls may take only part of it if a buffer limit is set
+        let count = cc.writer().write(&self.early_data_copy).map_err(|e| {
+            TlsError::Internal(format!(
+                "Unexpected error from ClientConnection::writer.write: {e}"
+            ))
+        })?;
+        self.early_data_copy.drain(..count);
+        self.bytes_since_refresh += count as u64;
+        Ok(count > 0)
+    }
+
+    // Check the negotiated version as soon as it is known, aborting
+    // `ext.wr` if it isn't allowed
+    fn check_handshake(&mut self, mut ext: PBufRdWr) -> Result<(), TlsError> {
+        let Some(ref cc) = self.cc else {
+            return Ok(());
+        };
+        if let Err(e) = crate::check_version(&self.allowed_versions, cc.protocol_version()) {
+            if !ext.wr.is_eof() {
+                ext.wr.abort();
+                self.outbound_termination.get_or_insert(Termination::Abort);
+            }
+            return Err(e);
+        }
+        Ok(())
+    }
+
+    // ClientConnection -> ext.wr.  Returns `true` if anything was
+    // written.
+    fn write_ext(&mut self, mut ext: PBufRdWr) -> Result<bool, TlsError> {
+        let Some(ref mut cc) = self.cc else {
+            return Ok(false);
+        };
+        if !cc.wants_write() || ext.wr.is_eof() {
+            return Ok(false);
+        }
+        // We're not expecting any error from this as PipeBuf Write
+        // implementation doesn't return Err and `write_tls` is just
+        // copying from an internal Rustls buffer.
+        cc.write_tls(&mut Tee {
+            inner: &mut ext.wr,
+            extras: self.extras.as_deref_mut(),
+        })
+        .map_err(|e| {
+            TlsError::Internal(format!(
+                "Unexpected error from ClientConnection::write_tls: {e}"
+            ))
+        })?;
+        self.flight_sent |= cc.is_handshaking();
+        if self.single_record {
+            ext.wr.push();
+        }
+        // If we've done a `send_close_notify` and Rustls has nothing
+        // more to write, it's time to close the TLS outgoing stream too
+        if self.close_notify_sent && !cc.wants_write() && self.close_transport {
+            ext.wr.close();
+            self.outbound_termination.get_or_insert(Termination::Close);
+        }
+        Ok(true)
+    }
+
+    // ext.rd -> ClientConnection, taking any bytes passed at creation
+    // first, then process the new packets.  Returns the number of
+    // bytes of plain-text waiting in Rustls, or `None` if nothing was
+    // read.  If Rustls fails, its alert is flushed to `ext.wr` first,
+    // or `ext.wr` is aborted if alerts are disabled.
+    fn read_ext(&mut self, mut ext: PBufRdWr) -> Result<Option<usize>, TlsError> {
+        let Some(ref mut cc) = self.cc else {
+            return Ok(None);
+        };
+        // Rustls stops asking for more whilst it holds plain-text,
+        // but whilst paused, records are still read so that alerts,
+        // `close_notify` and key updates are handled, until Rustls
+        // holds as much as it will take
+        let wants_read = cc.wants_read()
+            || (self.paused
+                && !self.close_notify_received
+                && self.plaintext_waiting <= record::RECEIVED_PLAINTEXT_LIMIT);
+        if !wants_read || (ext.rd.is_empty() && self.prebuffered.is_empty()) {
+            return Ok(None);
+        }
+        // Data arriving after a handshake flight was sent is the
+        // reply, completing a round trip
+        if self.flight_sent && cc.is_handshaking() {
+            self.round_trips = self.round_trips.saturating_add(1);
+            self.flight_sent = false;
+        }
+        // We don't expect any error from this.  Reading from a slice
+        // doesn't return an error.  The call may return an error if
+        // its buffer is full, but we only call it when it wants more
+        // data.
+        let from_prebuffered = !self.prebuffered.is_empty();
+        let data = if from_prebuffered {
+            // Bytes passed at creation come before ext.rd
+            &self.prebuffered[..]
+        } else if self.single_record {
+            let data = ext.rd.data();
+            &data[..record::first_record_len(data)]
+        } else {
+            ext.rd.data()
+        };
+        if let Some(mut check) = self.extras.as_ref().and_then(|x| x.record_limit) {
+            check.scan(data)?;
+        }
+        let count = cc.read_tls(&mut &data[..]).map_err(|e| {
+            TlsError::Internal(format!(
+                "Unexpected failure from ClientConnection::read_tls: {e}"
+            ))
+        })?;
+        if let Some(ref mut extras) = self.extras {
+            if let Some(ref mut limit) = extras.record_limit {
+                limit.scan(&data[..count])?;
+            }
+            extras.observe(false, &data[..count]);
+        }
+        if from_prebuffered {
+            self.prebuffered.drain(..count);
+        } else {
+            ext.rd.consume(count);
+        }
+
+        let state = match cc.process_new_packets() {
+            Ok(state) => state,
+            Err(e) => {
+                if !ext.wr.is_eof() {
+                    if self.alert_on_error {
+                        // Flush the fatal alert that Rustls has queued,
+                        // so that the peer learns the reason.  Any
+                        // failure is kept for `recent_errors`.
+                        if let Err(e) = cc.write_tls(&mut Tee {
+                            inner: &mut ext.wr,
+                            extras: self.extras.as_deref_mut(),
+                        }) {
+                            crate::record_error(
+                                &mut self.recent_errors,
+                                &TlsError::Internal(format!("Failed to send alert: {e}")),
+                            );
+                        }
+                    } else {
+                        // Close without revealing the reason
+                        ext.wr.abort();
+                        self.outbound_termination.get_or_insert(Termination::Abort);
+                    }
+                }
+                return Err(TlsError::from(e));
+            }
+        };
+        self.close_notify_received |= state.peer_has_closed();
+        self.plaintext_waiting = state.plaintext_bytes_to_read();
+        Ok(Some(self.plaintext_waiting))
+    }
+
+    // ClientConnection -> int.wr, for `available` bytes of plain-text
+    // just decrypted, passing on at most `limit` bytes.  Whilst
+    // paused, or beyond the limit, the plain-text is held in Rustls.
+    fn pass_plaintext(
+        &mut self,
+        available: usize,
+        mut int: PBufWr,
+        limit: usize,
+    ) -> Result<(), TlsError> {
+        let Some(ref mut cc) = self.cc else {
+            return Ok(());
+        };
+        if self.paused && available > 0 {
+            // Leave it in Rustls until resumed
+            self.plaintext_held = true;
+        } else if !int.is_eof() {
+            let read_len = available.min(limit);
+            if read_len < available {
+                // Leave the rest in Rustls until `int.wr` has room
+                self.plaintext_held = true;
+            }
+            if read_len > 0 {
+                self.bytes_since_refresh += read_len as u64;
+                self.plaintext_waiting = self.plaintext_waiting.saturating_sub(read_len);
+                if let Err(e) = int.input_from(&mut cc.reader(), read_len) {
+                    match e.kind() {
+                        ErrorKind::WouldBlock => self.would_block_count += 1,
+                        ErrorKind::UnexpectedEof => int.abort(),
+                        _ => return Err(TlsError::Decode(format!("TLS read error: {e}"))),
+                    }
+                }
+                if self.single_record {
+                    int.push();
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the rarely used state, allocating it on first use
+    fn extras(&mut self) -> &mut Extras {
+        self.extras.get_or_insert_with(Default::default)
+    }
+
+    // Consume the chunk last returned by `process_read_borrowed`
+    fn release_borrowed(&mut self) {
+        if self.borrowed_len > 0 {
+            if let Some(ref mut cc) = self.cc {
+                cc.reader().consume(self.borrowed_len);
+            }
+            self.borrowed_len = 0;
+        }
+    }
+
+    // Body of `process`, without the recording of errors
+    fn process_inner(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) -> Result<bool, TlsError> {
+        let before = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
+        let mut capped = false;
+
+        // Plain-text is passed to `int.wr` whilst it holds no more
+        // than `watermark` bytes, and at most `limit` bytes at a time.
+        // Single-record mode waits for each record to be consumed.
+        let (watermark, limit) = if self.single_record {
+            let limit = self
+                .max_plaintext_buffered
+                .min(record::MAX_RECORD_PLAINTEXT);
+            (0, limit)
+        } else {
+            (self.max_plaintext_buffered, self.max_plaintext_buffered)
+        };
+
+        // Pass on any plain-text held in Rustls whilst paused or
+        // whilst `int.wr` was full
+        if self.plaintext_held
+            && !self.paused
+            && !int.wr.is_eof()
+            && !int.wr.exceeds_limit(watermark)
+        {
+            self.drain_plaintext(int.wr.reborrow(), watermark, limit)?;
+            // Anything more waits for `int.wr` to be consumed
+            self.plaintext_held = int.wr.exceeds_limit(watermark);
+        }
+
+        if self.transport_failed {
+            crate::abort_all(&mut ext, &mut int);
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        } else if self.cc.is_some() {
+            let mut held = false;
+            let mut iterations = 0;
             loop {
+                // Hand control back to the caller if this is taking
+                // too long, reporting activity so that it calls again
+                iterations += 1;
+                if iterations > self.max_iterations {
+                    capped = true;
+                    break;
+                }
+
+                // Check the negotiated version as soon as it is known
+                self.check_handshake(ext.reborrow())?;
+
                 // ClientConnection -> ext.wr
-                if cc.wants_write() && !ext.wr.is_eof() {
-                    // We're not expecting any error from this as
-                    // PipeBuf Write implementation doesn't return Err
-                    // and `write_tls` is just copying from an
-                    // internal Rustls buffer.
-                    cc.write_tls(&mut ext.wr).map_err(|e| {
-                        TlsError(format!(
-                            "Unexpected error from ClientConnection::write_tls: {e}"
-                        ))
-                    })?;
-                    // If we've done a `send_close_notify` and Rustls
-                    // has nothing more to write, it's time to close
-                    // the TLS outgoing stream too
-                    if int.rd.is_done() && !cc.wants_write() {
-                        ext.wr.close();
-                    }
+                if self.write_ext(ext.reborrow())? {
                     continue;
                 }
 
-                // int.rd -> ClientConnection; flushes only on "push"
-                if !cc.is_handshaking() {
-                    if !int.rd.is_empty() {
+                // Early data rejected by the server goes again as
+                // normal data, ahead of anything new from int.rd
+                if !self.paused && self.resend_early_data()? {
+                    continue;
+                }
+
+                let Some(ref mut cc) = self.cc else {
+                    break;
+                };
+                if !cc.is_handshaking() && self.draining {
+                    // Close once all accepted plain-text has been
+                    // encrypted, ignoring anything new in int.rd
+                    if !self.close_notify_sent {
+                        cc.send_close_notify();
+                        self.close_notify_sent = true;
+                        continue;
+                    }
+                } else if !cc.is_handshaking() && !self.paused && self.early_data_copy.is_empty() {
+                    // int.rd -> ClientConnection; flushes only on "push"
+                    let hold = self.coalesce_held < self.coalesce_max
+                        && int.rd.data().len() < record::MAX_RECORD_PLAINTEXT
+                        && !int.rd.has_pending_eof();
+                    if !int.rd.is_empty() && hold {
+                        // Leave small writes to collect for a later call
+                        held = true;
+                    } else if !int.rd.is_empty() {
+                        let waiting = int.rd.data().len();
                         // Not expecting any error
-                        int.rd.output_to(&mut cc.writer(), false).map_err(|e| {
-                            TlsError(format!(
+                        let map_err = |e: std::io::Error| {
+                            TlsError::Internal(format!(
                                 "Unexpected error from ClientConnection::writer.write: {e}"
                             ))
-                        })?;
-                        continue;
+                        };
+                        if self.single_record || self.quota_remaining.is_some() {
+                            let data = int.rd.data();
+                            let mut len = data.len();
+                            if self.single_record {
+                                len = len.min(record::MAX_RECORD_PLAINTEXT);
+                            }
+                            if let Some(remaining) = self.quota_remaining {
+                                len = len.min(usize::try_from(remaining).unwrap_or(usize::MAX));
+                            }
+                            let count = cc.writer().write(&data[..len]).map_err(map_err)?;
+                            int.rd.consume(count);
+                            if let Some(ref mut remaining) = self.quota_remaining {
+                                *remaining -= count as u64;
+                                if *remaining == 0 {
+                                    // Quota used up, so close cleanly
+                                    self.draining = true;
+                                }
+                            }
+                        } else {
+                            int.rd.output_to(&mut cc.writer(), false).map_err(map_err)?;
+                        }
+                        self.coalesce_held = 0;
+                        self.bytes_since_refresh += (waiting - int.rd.data().len()) as u64;
+                        if int.rd.data().len() < waiting {
+                            continue;
+                        }
+                        // Rustls accepted nothing because its buffer
+                        // limit has been reached, so don't spin
                     }
-                    // int.rd is empty
-                    if int.rd.consume_eof() {
-                        if int.rd.is_aborted() {
-                            // For Abort, don't terminate the TLS protocol
-                            // nicely.  This will result in an
-                            // UnexpectedEof at the other end.  It should
-                            // be possible (on the other end of int.rd) to
-                            // write data, push, and abort and that data
-                            // will be sent before the abort of the
-                            // ext.wr.
-                            ext.wr.abort();
+                    // int.rd is empty, unless held or stalled
+                    if int.rd.is_empty() && int.rd.consume_eof() {
+                        let action = if int.rd.is_aborted() {
+                            self.abort_action
                         } else {
-                            // Close cleanly with a "close_notify"
-                            cc.send_close_notify();
+                            self.clean_close_action
+                        };
+                        match action {
+                            CloseAction::Abort => {
+                                // For Abort, don't terminate the TLS
+                                // protocol nicely.  This will result in
+                                // an UnexpectedEof at the other end.  It
+                                // should be possible (on the other end
+                                // of int.rd) to write data, push, and
+                                // abort and that data will be sent
+                                // before the abort of the ext.wr.
+                                ext.wr.abort();
+                                self.outbound_termination.get_or_insert(Termination::Abort);
+                            }
+                            CloseAction::CloseNotify => {
+                                // Close cleanly with a "close_notify"
+                                cc.send_close_notify();
+                                self.close_notify_sent = true;
+                            }
+                            CloseAction::KeepOpen => (),
                         }
                         continue;
                     }
                 }
 
-                // ext.rd -> ClientConnection
-                if cc.wants_read() && !ext.rd.is_empty() {
-                    // We don't expect any error from this.  The
-                    // PipeBuf Read implementation doesn't return an
-                    // error if there are bytes.  The call may return
-                    // an error if its buffer is full, but we only
-                    // call it when it wants more data.
-                    cc.read_tls(&mut ext.rd).map_err(|e| {
-                        TlsError(format!(
-                            "Unexpected failure from ClientConnection::read_tls: {e}"
-                        ))
-                    })?;
-
-                    let state = cc
-                        .process_new_packets()
-                        .map_err(|e| TlsError(format!("TLS stream error: {e}")))?;
-
-                    // ClientConnection -> int.wr
-                    if !int.wr.is_eof() {
-                        let read_len = state.plaintext_bytes_to_read();
-                        if read_len > 0 {
-                            if let Err(e) = int.wr.input_from(&mut cc.reader(), read_len) {
-                                match e.kind() {
-                                    ErrorKind::WouldBlock => (),
-                                    ErrorKind::UnexpectedEof => int.wr.abort(),
-                                    _ => return Err(TlsError(format!("TLS read error: {e}"))),
-                                }
-                            }
-                        }
+                // ext.rd -> ClientConnection, unless the internal side
+                // has closed int.wr to refuse further data, or
+                // plain-text is being held until `int.wr` has room.
+                // Whilst paused, nothing goes to `int.wr`, so reading
+                // continues.
+                let refused = (int.wr.is_eof() && !cc.is_handshaking())
+                    || (!self.paused && (self.plaintext_held || int.wr.exceeds_limit(watermark)));
+                if !refused {
+                    if let Some(available) = self.read_ext(ext.reborrow())? {
+                        // ClientConnection -> int.wr
+                        self.pass_plaintext(available, int.wr.reborrow(), limit)?;
+                        continue;
                     }
-                    continue;
                 }
 
                 // Pass through EOF from external side.  For the case
@@ -157,7 +1628,12 @@ impl TlsClient {
                 // external side, so in that case just pass the EOF
                 // through even thought there is pending data.
                 if ext.rd.has_pending_eof()
-                    && (ext.rd.is_aborted() || ext.rd.is_empty() || int.rd.is_done())
+                    && !self.paused
+                    && !self.plaintext_held
+                    && (ext.rd.is_aborted()
+                        || ext.rd.is_empty()
+                        || int.rd.is_done()
+                        || self.close_notify_sent)
                 {
                     ext.rd.consume_eof();
                     if !int.wr.is_eof() {
@@ -173,13 +1649,82 @@ impl TlsClient {
                 // Nothing left to do
                 break;
             }
-        } else {
+            if held {
+                // Report activity, so that a caller looping on
+                // `process` counts down the delay and sends the data
+                self.coalesce_held += 1;
+                capped = true;
+            }
+        } else if !self.paused {
             // TLS disabled: Pass data through unchanged
-            int.rd.forward(ext.wr.reborrow());
+            if !self.prebuffered.is_empty() {
+                int.wr.append(&self.prebuffered);
+                self.prebuffered = Vec::new();
+            }
+            if !self.draining {
+                int.rd.forward(ext.wr.reborrow());
+                if int.rd.is_done() {
+                    let how = if int.rd.is_aborted() {
+                        Termination::Abort
+                    } else {
+                        Termination::Close
+                    };
+                    self.outbound_termination.get_or_insert(how);
+                }
+            } else if !ext.wr.is_eof() {
+                ext.wr.close();
+                self.outbound_termination.get_or_insert(Termination::Close);
+            }
             ext.rd.forward(int.wr.reborrow());
         }
 
+        self.read_closed |= int.wr.is_eof();
+        self.write_closed |= int.rd.is_done() || ext.wr.is_eof();
+
         let after = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
-        Ok(after != before)
+        Ok(capped || after != before)
+    }
+
+    /// Do a whole request/response exchange over a blocking
+    /// `transport`, for the simplest clients: complete the handshake,
+    /// send `request`, close the outgoing stream, and then return
+    /// everything received until the server closes its side.
+    /// Requires the `sync` cargo feature.
+    #[cfg(feature = "sync")]
+    pub fn request(
+        &mut self,
+        transport: &mut (impl std::io::Read + std::io::Write),
+        request: &[u8],
+    ) -> Result<Vec<u8>, TlsError> {
+        crate::sync::exchange(|ext, int| self.process(ext, int), transport, request)
+    }
+
+    /// Process as for [`TlsClient::process`], but return a [`Progress`]
+    /// report giving more detail about what happened during the
+    /// call.  For example, this allows a "connection ready" event to
+    /// be fired on exactly the call where the handshake completed.
+    pub fn process_report(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<Progress, TlsError> {
+        let handshaking = self.cc.as_ref().is_some_and(|cc| cc.is_handshaking());
+        let close_notify_sent = self.close_notify_sent;
+        let close_notify_received = self.close_notify_received;
+        let int_done = int.rd.is_done();
+        let ext_done = ext.rd.is_done();
+        let activity = self.process(ext.reborrow(), int.reborrow())?;
+        Ok(Progress {
+            activity,
+            just_completed_handshake: handshaking
+                && !self.cc.as_ref().is_some_and(|cc| cc.is_handshaking()),
+            sent_close_notify: !close_notify_sent && self.close_notify_sent,
+            received_close_notify: !close_notify_received && self.close_notify_received,
+            sent_abort: !int_done
+                && int.rd.is_done()
+                && int.rd.is_aborted()
+                && (self.cc.is_none() || self.abort_action == CloseAction::Abort),
+            received_abort: !ext_done && ext.rd.is_done() && ext.rd.is_aborted(),
+        })
     }
 }
@@ -0,0 +1,176 @@
+//! Stateless parsing of the unencrypted start of a TLS handshake,
+//! for `inspect`
+
+use crate::HandshakeInfo;
+use rustls::{CipherSuite, ProtocolVersion};
+
+/// Handshake message type for ClientHello
+const CLIENT_HELLO: u8 = 1;
+
+/// Handshake message type for ServerHello
+const SERVER_HELLO: u8 = 2;
+
+/// Extension types
+const EXT_SERVER_NAME: u16 = 0;
+const EXT_ALPN: u16 = 16;
+const EXT_SUPPORTED_VERSIONS: u16 = 43;
+
+/// Parse the complete records at the start of `bytes`, which must
+/// already have been checked to look like TLS
+pub(crate) fn parse(mut bytes: &[u8]) -> HandshakeInfo {
+    let mut info = HandshakeInfo::default();
+    let mut handshake = Vec::new();
+    let mut clear = true;
+    while bytes.len() >= 5 {
+        let len = 5 + usize::from(u16::from_be_bytes([bytes[3], bytes[4]]));
+        if bytes.len() < len {
+            break;
+        }
+        info.records += 1;
+        match bytes[0] {
+            // Handshake messages are only readable up to the
+            // ChangeCipherSpec or the first encrypted record
+            22 if clear => handshake.extend_from_slice(&bytes[5..len]),
+            20 | 23 => clear = false,
+            _ => (),
+        }
+        bytes = &bytes[len..];
+    }
+
+    let mut data = &handshake[..];
+    while let Some((msg_type, body, rest)) = message(data) {
+        info.handshake_messages += 1;
+        // Malformed hellos are skipped, leaving the fields unset
+        match msg_type {
+            CLIENT_HELLO => {
+                let _ = client_hello(body, &mut info);
+            }
+            SERVER_HELLO => {
+                let _ = server_hello(body, &mut info);
+            }
+            _ => (),
+        }
+        data = rest;
+    }
+    info
+}
+
+/// Get the fields of interest from a ClientHello
+fn client_hello(body: &[u8], info: &mut HandshakeInfo) -> Option<()> {
+    let (legacy_version, body) = take_u16(body)?;
+    let body = body.get(32..)?; // Random
+    let (_, body) = take_vec8(body)?; // Session ID
+    let (_, body) = take_vec16(body)?; // Cipher suites
+    let (_, body) = take_vec8(body)?; // Compression methods
+    info.offered_versions = vec![ProtocolVersion::from(legacy_version)];
+    let (mut exts, _) = take_vec16(body)?;
+    while let Some((ext_type, ext, rest)) = extension(exts) {
+        match ext_type {
+            EXT_SERVER_NAME => {
+                let (mut names, _) = take_vec16(ext)?;
+                while let Some((&name_type, rest)) = names.split_first() {
+                    let (name, rest) = take_vec16(rest)?;
+                    if name_type == 0 {
+                        info.server_name = Some(String::from_utf8_lossy(name).into_owned());
+                    }
+                    names = rest;
+                }
+            }
+            EXT_ALPN => info.alpn_protocols = alpn_list(ext)?,
+            EXT_SUPPORTED_VERSIONS => {
+                let (list, _) = take_vec8(ext)?;
+                info.offered_versions = list
+                    .chunks_exact(2)
+                    .map(|v| u16::from_be_bytes([v[0], v[1]]))
+                    .filter(|&v| !is_grease(v))
+                    .map(ProtocolVersion::from)
+                    .collect();
+            }
+            _ => (),
+        }
+        exts = rest;
+    }
+    Some(())
+}
+
+/// Get the fields of interest from a ServerHello
+fn server_hello(body: &[u8], info: &mut HandshakeInfo) -> Option<()> {
+    let (legacy_version, body) = take_u16(body)?;
+    let body = body.get(32..)?; // Random
+    let (_, body) = take_vec8(body)?; // Session ID
+    let (suite, body) = take_u16(body)?;
+    let body = body.get(1..)?; // Compression method
+    info.version = Some(ProtocolVersion::from(legacy_version));
+    info.cipher_suite = Some(CipherSuite::from(suite));
+    // Extensions are optional before TLS 1.3
+    let Some((mut exts, _)) = take_vec16(body) else {
+        return Some(());
+    };
+    while let Some((ext_type, ext, rest)) = extension(exts) {
+        match ext_type {
+            EXT_ALPN => info.alpn_protocols = alpn_list(ext)?,
+            EXT_SUPPORTED_VERSIONS => {
+                info.version = Some(ProtocolVersion::from(take_u16(ext)?.0));
+            }
+            _ => (),
+        }
+        exts = rest;
+    }
+    Some(())
+}
+
+/// Parse the protocol names in an ALPN extension
+fn alpn_list(ext: &[u8]) -> Option<Vec<Vec<u8>>> {
+    let (mut list, _) = take_vec16(ext)?;
+    let mut protocols = Vec::new();
+    while !list.is_empty() {
+        let (name, rest) = take_vec8(list)?;
+        protocols.push(name.to_vec());
+        list = rest;
+    }
+    Some(protocols)
+}
+
+/// Check for an RFC 8701 GREASE value, which clients add to lists
+/// to keep servers tolerant of unknown values
+fn is_grease(value: u16) -> bool {
+    value & 0x0F0F == 0x0A0A && value >> 8 == value & 0xFF
+}
+
+/// Split the complete handshake message at the start of `data` into
+/// its type, its body and the data following it
+fn message(data: &[u8]) -> Option<(u8, &[u8], &[u8])> {
+    let (&msg_type, data) = data.split_first()?;
+    let len = data.get(..3)?;
+    let len = usize::from(len[0]) << 16 | usize::from(len[1]) << 8 | usize::from(len[2]);
+    let data = &data[3..];
+    (data.len() >= len).then(|| (msg_type, &data[..len], &data[len..]))
+}
+
+/// Split the extension at the start of `data` into its type, its
+/// contents and the data following it
+fn extension(data: &[u8]) -> Option<(u16, &[u8], &[u8])> {
+    let (ext_type, data) = take_u16(data)?;
+    let (ext, rest) = take_vec16(data)?;
+    Some((ext_type, ext, rest))
+}
+
+/// Split off a big-endian 16-bit value
+fn take_u16(data: &[u8]) -> Option<(u16, &[u8])> {
+    let value = data.get(..2)?;
+    Some((u16::from_be_bytes([value[0], value[1]]), &data[2..]))
+}
+
+/// Split off a vector with an 8-bit length prefix
+fn take_vec8(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (&len, data) = data.split_first()?;
+    let len = usize::from(len);
+    (data.len() >= len).then(|| data.split_at(len))
+}
+
+/// Split off a vector with a 16-bit length prefix
+fn take_vec16(data: &[u8]) -> Option<(&[u8], &[u8])> {
+    let (len, data) = take_u16(data)?;
+    let len = usize::from(len);
+    (data.len() >= len).then(|| data.split_at(len))
+}
@@ -75,6 +75,11 @@
 
 #![forbid(unsafe_code)]
 
+use pipebuf::{PBufRdWr, PBufWr, PipeBufPair};
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::time_provider::TimeProvider;
+use rustls::{AlertDescription, CipherSuite, NamedGroup, ProtocolVersion};
+
 pub use rustls;
 
 #[cfg(all(not(feature = "unbuffered"), not(feature = "buffered")))]
@@ -83,7 +88,13 @@ compile_error!("Select a crate feature: either `buffered` or `unbuffered`");
 // If they select both `unbuffered` and `buffered`, default to
 // `buffered` for 0.23, since that is more mature
 #[cfg(feature = "buffered")]
+mod capture;
+mod cert;
+#[cfg(feature = "buffered")]
 mod client;
+mod inspect;
+#[cfg(feature = "buffered")]
+mod record;
 #[cfg(feature = "buffered")]
 mod server;
 #[cfg(feature = "buffered")]
@@ -93,17 +104,525 @@ pub use server::TlsServer;
 
 #[cfg(not(feature = "buffered"))]
 mod unbuf;
+
+#[cfg(feature = "sync")]
+mod sync;
+#[cfg(feature = "testing")]
+pub mod testing;
 #[cfg(not(feature = "buffered"))]
 pub use unbuf::{TlsClient, TlsServer};
+mod verify;
+pub use verify::{ClientCertCallback, ServerCertCallback};
 
-/// Error in TLS processing
-#[derive(Debug)]
-pub struct TlsError(String);
+/// Error in TLS processing.  More variants may be added in future
+/// releases, so matches need a wildcard arm.
+#[derive(Debug, Clone)]
+#[non_exhaustive]
+pub enum TlsError {
+    /// Rustls reported an error, e.g. a protocol violation by the
+    /// peer or an invalid certificate.  The context is kept alongside
+    /// the Rustls error so that the message says which step failed.
+    Rustls {
+        /// What was being done when the error occurred
+        context: &'static str,
+        /// The error from Rustls
+        error: rustls::Error,
+    },
+    /// Outgoing TLS data could not be encoded or encrypted
+    Encode(String),
+    /// Incoming TLS data could not be decoded or read
+    Decode(String),
+    /// The connection failed a check made by this crate, e.g. a
+    /// missing SNI name, a peer certificate rejected by a callback,
+    /// or an incomplete close found by `verify_complete`
+    Check(String),
+    /// A call was made in a state or with arguments that it can't
+    /// handle, e.g. a TLS-only call when TLS is disabled
+    Usage(String),
+    /// The transport failed, for the blocking helpers
+    Transport(String),
+    /// Something unexpected happened, which indicates a bug in this
+    /// crate or in Rustls
+    Internal(String),
+}
+
+impl TlsError {
+    /// Convert a Rustls error, with `context` describing what failed
+    pub(crate) fn rustls(context: &'static str, error: rustls::Error) -> Self {
+        Self::Rustls { context, error }
+    }
+
+    /// Check whether the handshake failed because both ends have
+    /// ALPN protocols configured but have none in common.  On the
+    /// server this is reported when the ClientHello is processed, and
+    /// on the client when the server's `no_application_protocol`
+    /// alert arrives.
+    pub fn is_no_application_protocol(&self) -> bool {
+        matches!(
+            self,
+            Self::Rustls {
+                error: rustls::Error::NoApplicationProtocol
+                    | rustls::Error::AlertReceived(AlertDescription::NoApplicationProtocol),
+                ..
+            }
+        )
+    }
+}
+
+impl From<rustls::Error> for TlsError {
+    fn from(error: rustls::Error) -> Self {
+        Self::rustls("TLS stream error", error)
+    }
+}
 
-impl std::error::Error for TlsError {}
+impl std::error::Error for TlsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Self::Rustls { error, .. } => Some(error),
+            _ => None,
+        }
+    }
+}
 
 impl std::fmt::Display for TlsError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.0)
+        match self {
+            Self::Rustls { error, .. } if self.is_no_application_protocol() => {
+                write!(f, "No common ALPN protocol: {error}")
+            }
+            Self::Rustls { context, error } => write!(f, "{context}: {error}"),
+            Self::Encode(msg)
+            | Self::Decode(msg)
+            | Self::Check(msg)
+            | Self::Usage(msg)
+            | Self::Transport(msg)
+            | Self::Internal(msg) => write!(f, "{msg}"),
+        }
+    }
+}
+
+/// Summary of a wrapper's mode and negotiated parameters, suitable
+/// for logging or for persisting along with other connection
+/// metadata.  This contains no key material.  With the `serde`
+/// feature enabled, it can be serialized and deserialized.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct ConnectionDescriptor {
+    /// `true` for a server, `false` for a client
+    pub server: bool,
+    /// `true` if TLS is enabled, `false` for passthrough
+    pub tls: bool,
+    /// Negotiated protocol version, e.g. `"TLSv1_3"`
+    pub protocol_version: Option<String>,
+    /// IANA name of the negotiated cipher suite
+    pub cipher_suite: Option<String>,
+    /// Negotiated ALPN protocol
+    pub alpn_protocol: Option<Vec<u8>>,
+}
+
+/// Negotiated cryptographic parameters of a connection, as returned
+/// by `crypto_summary`.  Rustls doesn't expose the signature scheme
+/// used in the handshake, so that can't be included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CryptoSummary {
+    /// Negotiated protocol version
+    pub version: ProtocolVersion,
+    /// Negotiated cipher suite
+    pub cipher_suite: CipherSuite,
+    /// Key exchange group, or `None` if no key exchange took place,
+    /// i.e. for a resumed TLS 1.2 session
+    pub kx_group: Option<NamedGroup>,
+}
+
+/// Report of what happened during a call to `process_report`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Progress {
+    /// There was activity, as for the `bool` returned by `process`
+    pub activity: bool,
+    /// The TLS handshake completed during this call
+    pub just_completed_handshake: bool,
+    /// Our `close_notify` was queued for sending during this call
+    pub sent_close_notify: bool,
+    /// The peer's `close_notify` arrived during this call
+    pub received_close_notify: bool,
+    /// An abort from the internal side was passed on to `ext.wr`
+    /// during this call
+    pub sent_abort: bool,
+    /// An abort from the external side was passed on to `int.wr`
+    /// during this call
+    pub received_abort: bool,
+}
+
+/// How the wrapper ended the outgoing stream on `ext.wr`, as reported
+/// by `outbound_termination`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Termination {
+    /// `ext.wr` was closed cleanly, e.g. the transport could be shut
+    /// down with a FIN
+    Close,
+    /// `ext.wr` was aborted, e.g. the transport could be reset
+    Abort,
+}
+
+/// Why the connection was ended by the transport rather than by the
+/// TLS protocol, as reported by `close_reason`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum CloseReason {
+    /// The transport failed, as reported with `transport_error`
+    TransportError,
+    /// The peer vanished without a clean shutdown, e.g. a connection
+    /// reset, as reported with `notify_peer_reset`
+    RemoteReset,
+}
+
+/// What to send to the peer when the internal side ends `int.rd`, as
+/// set by `set_internal_close_mapping`
+#[cfg(feature = "buffered")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CloseAction {
+    /// Send a `close_notify` and then close `ext.wr`
+    CloseNotify,
+    /// Abort `ext.wr` without a `close_notify`, so that the peer sees
+    /// a truncated TLS stream
+    Abort,
+    /// Send nothing, leaving the TLS stream open.  The connection can
+    /// still be closed later, e.g. with `begin_drain`.
+    KeepOpen,
+}
+
+/// Progress of a clean shutdown, as reported by `drive_shutdown`
+#[cfg(feature = "buffered")]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownProgress {
+    /// Our `close_notify` has not yet been written out to `ext.wr`
+    Flushing,
+    /// Our side is closed, and we're waiting for the peer's
+    /// `close_notify` or end-of-file
+    AwaitingPeerClose,
+    /// Both directions are closed.  The transport can be closed.
+    Done,
+}
+
+/// Amount of data waiting in the pipes passed to `process`, for
+/// diagnostics
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PipeLevels {
+    /// Bytes of TLS protocol data in `ext.rd` not yet processed
+    pub ext_rd: usize,
+    /// Bytes of TLS protocol data in `ext.wr` not yet sent
+    pub ext_wr: usize,
+    /// Bytes of plaintext in `int.rd` not yet encrypted
+    pub int_rd: usize,
+    /// Bytes of plaintext in `int.wr` not yet consumed
+    pub int_wr: usize,
+}
+
+/// Report the amount of data waiting in the `ext` and `int` pipes,
+/// for example for a connection-health endpoint.  See
+/// [`PipeLevels`].
+pub fn pipe_levels(ext: &PBufRdWr, int: &PBufRdWr) -> PipeLevels {
+    PipeLevels {
+        ext_rd: ext.rd.data().len(),
+        ext_wr: wr_level(&ext.wr),
+        int_rd: int.rd.data().len(),
+        int_wr: wr_level(&int.wr),
+    }
+}
+
+// Length of the data in the pipe written by `wr`.  A `PBufWr` only
+// reports whether the pipe holds more than a given amount, so search
+// for the length that way.
+fn wr_level(wr: &PBufWr) -> usize {
+    let (mut lo, mut hi) = (0, usize::MAX);
+    while lo < hi {
+        let mid = lo + (hi - lo) / 2;
+        if wr.exceeds_limit(mid) {
+            lo = mid + 1;
+        } else {
+            hi = mid;
+        }
+    }
+    lo
+}
+
+/// The negotiated parameters of a [`TlsClient`] or [`TlsServer`],
+/// so that [`compatible`] can compare either
+pub trait TlsEndpoint {
+    /// Get the negotiated protocol version, or `None` if not yet
+    /// known or if TLS is disabled
+    fn protocol_version(&self) -> Option<ProtocolVersion>;
+
+    /// Get the negotiated ALPN protocol, if any
+    fn alpn_protocol(&self) -> Option<&[u8]>;
+}
+
+impl TlsEndpoint for TlsClient {
+    fn protocol_version(&self) -> Option<ProtocolVersion> {
+        TlsClient::protocol_version(self)
+    }
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        TlsClient::alpn_protocol(self)
+    }
+}
+
+impl TlsEndpoint for TlsServer {
+    fn protocol_version(&self) -> Option<ProtocolVersion> {
+        TlsServer::protocol_version(self)
+    }
+
+    fn alpn_protocol(&self) -> Option<&[u8]> {
+        TlsServer::alpn_protocol(self)
+    }
+}
+
+/// Check whether two connections negotiated compatible parameters,
+/// for example before a proxy bridges plain-text between an inbound
+/// and an outbound TLS connection.  Both must have TLS enabled and
+/// have negotiated the same protocol version and the same ALPN
+/// protocol (or both none).  Returns `false` until both versions are
+/// known.
+pub fn compatible(a: &impl TlsEndpoint, b: &impl TlsEndpoint) -> bool {
+    let version = a.protocol_version();
+    version.is_some() && version == b.protocol_version() && a.alpn_protocol() == b.alpn_protocol()
+}
+
+/// Check whether the first bytes received on a connection look like
+/// the start of a TLS handshake, for example to route connections
+/// on a port that multiplexes TLS and plain-text protocols before
+/// creating a wrapper.  This checks for a TLS record header with
+/// the handshake content type (22), a 3.x protocol version, and a
+/// plausible record length.  At least 5 bytes are required, so
+/// returns `false` if fewer are given.
+pub fn looks_like_tls(bytes: &[u8]) -> bool {
+    if bytes.len() < 5 {
+        return false;
+    }
+    let len = u16::from_be_bytes([bytes[3], bytes[4]]) as usize;
+    bytes[0] == 22 && bytes[1] == 3 && bytes[2] <= 4 && len > 0 && len <= 16384
+}
+
+/// Metadata visible in the unencrypted start of a TLS handshake, as
+/// returned by [`inspect`]
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+pub struct HandshakeInfo {
+    /// Number of complete TLS records, including encrypted ones
+    pub records: usize,
+    /// Number of complete unencrypted handshake messages
+    pub handshake_messages: usize,
+    /// SNI server name requested in a ClientHello
+    pub server_name: Option<String>,
+    /// ALPN protocols offered in a ClientHello, or the protocol
+    /// chosen in a TLS 1.2 ServerHello.  TLS 1.3 servers send their
+    /// choice encrypted.
+    pub alpn_protocols: Vec<Vec<u8>>,
+    /// Protocol versions offered in a ClientHello
+    pub offered_versions: Vec<ProtocolVersion>,
+    /// Protocol version chosen in a ServerHello
+    pub version: Option<ProtocolVersion>,
+    /// Cipher suite chosen in a ServerHello
+    pub cipher_suite: Option<CipherSuite>,
+}
+
+/// Extract metadata from the start of one direction of a captured
+/// TLS byte stream, e.g. for a passive analyzer.  This is stateless
+/// and separate from the wrappers: the bytes are only read.  Only
+/// complete records are examined, and handshake messages only up to
+/// the point where encryption starts.  Returns `None` if the bytes
+/// don't start with a TLS handshake record, as for
+/// [`looks_like_tls`].
+pub fn inspect(bytes: &[u8]) -> Option<HandshakeInfo> {
+    looks_like_tls(bytes).then(|| inspect::parse(bytes))
+}
+
+/// Estimate the total size of the TLS records needed to carry
+/// `plaintext_len` bytes of application data, including the record
+/// headers and AEAD overheads, for example to pre-size buffers.
+/// This assumes the maximum fragment size of 16KB.  A smaller
+/// `max_fragment_size` in the configuration means more records and
+/// so more overhead.  The estimate is exact for TLS 1.3 without
+/// padding, and for TLS 1.2 is an upper bound for the AEAD cipher
+/// suites supported by Rustls.
+pub fn tls_overhead(plaintext_len: usize, version: ProtocolVersion) -> usize {
+    const MAX_FRAGMENT: usize = 16384;
+    let per_record = match version {
+        // Header, inner content type, tag
+        ProtocolVersion::TLSv1_3 => 5 + 1 + 16,
+        // Header, explicit nonce, tag
+        _ => 5 + 8 + 16,
+    };
+    plaintext_len + plaintext_len.div_ceil(MAX_FRAGMENT) * per_record
+}
+
+/// Run a client and server connected back-to-back in the same
+/// process until no more progress is made, for example to test a
+/// protocol over TLS deterministically.  `client_int` carries
+/// plain-text between the client protocol handler (left) and
+/// `client` (right), `transport` carries TLS data between `client`
+/// (left) and `server` (right), and `server_int` carries plain-text
+/// between `server` (left) and the server protocol handler (right).
+///
+/// At most `max_steps` rounds of `process` calls are made, to avoid
+/// hanging in buggy scenarios.  Returns `Ok(true)` if everything
+/// became idle, or `Ok(false)` if the limit was reached first.
+pub fn run_pair(
+    client: &mut TlsClient,
+    server: &mut TlsServer,
+    client_int: &mut PipeBufPair,
+    transport: &mut PipeBufPair,
+    server_int: &mut PipeBufPair,
+    max_steps: usize,
+) -> Result<bool, TlsError> {
+    for _ in 0..max_steps {
+        let client_activity = client.process(transport.left(), client_int.right())?;
+        let server_activity = server.process(transport.right(), server_int.left())?;
+        if !client_activity && !server_activity {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Rustls time provider that calls a plain function
+#[derive(Debug)]
+pub(crate) struct Clock(pub(crate) fn() -> UnixTime);
+
+impl TimeProvider for Clock {
+    fn current_time(&self) -> Option<UnixTime> {
+        Some((self.0)())
+    }
+}
+
+/// Get the process-default crypto provider, failing instead of
+/// panicking if none has been installed
+pub(crate) fn default_provider(
+) -> Result<std::sync::Arc<rustls::crypto::CryptoProvider>, rustls::Error> {
+    rustls::crypto::CryptoProvider::get_default()
+        .cloned()
+        .ok_or_else(|| {
+            rustls::Error::General("No process-default crypto provider has been installed".into())
+        })
+}
+
+/// Build a client configuration trusting the platform's root
+/// certificates, using the process-default crypto provider.  Any
+/// errors from loading the trust store are included in the failure
+/// message if no usable certificates were found.
+#[cfg(feature = "native-roots")]
+pub(crate) fn native_roots_config() -> Result<std::sync::Arc<rustls::ClientConfig>, rustls::Error> {
+    let provider = default_provider()?;
+    let loaded = rustls_native_certs::load_native_certs();
+    let mut roots = rustls::RootCertStore::empty();
+    let (added, _) = roots.add_parsable_certificates(loaded.certs);
+    if added == 0 {
+        let mut msg = String::from("No usable certificates found in the platform trust store");
+        for e in loaded.errors {
+            msg.push_str(&format!("; {e}"));
+        }
+        return Err(rustls::Error::General(msg));
+    }
+    Ok(std::sync::Arc::new(
+        rustls::ClientConfig::builder_with_provider(provider)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_no_client_auth(),
+    ))
+}
+
+/// Get the DNS names from a certificate's subjectAltName extension,
+/// using the `rustls-webpki` parser that Rustls uses to verify it
+pub(crate) fn cert_dns_names(cert: &CertificateDer<'_>) -> Option<Vec<String>> {
+    let cert = webpki::EndEntityCert::try_from(cert).ok()?;
+    Some(cert.valid_dns_names().map(String::from).collect())
+}
+
+/// Plain-text volume, in both directions, after which
+/// `should_refresh_keys` recommends a key update.  This is well
+/// within the AEAD usage limits of all the TLS 1.3 cipher suites.
+#[cfg(feature = "buffered")]
+pub(crate) const DEFAULT_KEY_REFRESH_BYTES: u64 = 1 << 30;
+
+/// Default limit on the number of iterations of the loop within a
+/// single `process` call
+#[cfg(feature = "buffered")]
+pub(crate) const DEFAULT_MAX_ITERATIONS: u32 = 100_000;
+
+/// Number of recent errors kept for `recent_errors`
+const MAX_RECENT_ERRORS: usize = 8;
+
+/// Add an error to a list of recent errors, dropping the oldest if
+/// the list is full
+pub(crate) fn record_error(errors: &mut Vec<TlsError>, e: &TlsError) {
+    if errors.len() >= MAX_RECENT_ERRORS {
+        // Only a few are kept, so shifting them down is cheap
+        errors.remove(0);
+    }
+    errors.push(e.clone());
+}
+
+/// Check the arguments for `export_keying_material`, so that misuse
+/// gets a clear error rather than a Rustls internal error.  TLS 1.3
+/// derives the output with HKDF-Expand, which is limited to 255
+/// blocks of the hash output.
+#[cfg(feature = "buffered")]
+pub(crate) fn check_export(
+    conn: &rustls::CommonState,
+    len: usize,
+    label: &[u8],
+) -> Result<(), TlsError> {
+    if conn.is_handshaking() {
+        return Err(TlsError::Usage(
+            "Can't export keying material before the handshake is complete".into(),
+        ));
+    }
+    if len == 0 {
+        return Err(TlsError::Usage(
+            "Keying material output length must not be zero".into(),
+        ));
+    }
+    if label.is_empty() {
+        return Err(TlsError::Usage(
+            "Keying material label must not be empty".into(),
+        ));
+    }
+    if let Some(rustls::SupportedCipherSuite::Tls13(suite)) = conn.negotiated_cipher_suite() {
+        let max = 255 * suite.common.hash_provider.output_len();
+        if len > max {
+            return Err(TlsError::Usage(format!(
+                "Keying material output length of {len} bytes exceeds the TLS 1.3 \
+                 maximum of {max} bytes for this cipher suite"
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Check the negotiated protocol version against a per-connection
+/// restriction, if there is one
+pub(crate) fn check_version(
+    allowed: &Option<Vec<ProtocolVersion>>,
+    negotiated: Option<ProtocolVersion>,
+) -> Result<(), TlsError> {
+    match (allowed, negotiated) {
+        (Some(allowed), Some(version)) if !allowed.contains(&version) => Err(TlsError::Check(
+            format!("Negotiated protocol version {version:?} is not allowed for this connection"),
+        )),
+        _ => Ok(()),
+    }
+}
+
+/// Give up on the connection: discard all pending data and abort any
+/// outputs that are still open
+pub(crate) fn abort_all(ext: &mut PBufRdWr, int: &mut PBufRdWr) {
+    int.rd.consume(int.rd.data().len());
+    int.rd.consume_eof();
+    ext.rd.consume(ext.rd.data().len());
+    ext.rd.consume_eof();
+    if !ext.wr.is_eof() {
+        ext.wr.abort();
+    }
+    if !int.wr.is_eof() {
+        int.wr.abort();
     }
 }
@@ -0,0 +1,184 @@
+use crate::TlsError;
+
+/// Maximum plain-text payload of a single TLS record
+pub(crate) const MAX_RECORD_PLAINTEXT: usize = 16384;
+
+/// Limit on the decrypted plain-text that Rustls holds before
+/// `read_tls` fails.  This is fixed in Rustls, and is separate from
+/// the limit set by `set_buffer_limit`.
+pub(crate) const RECEIVED_PLAINTEXT_LIMIT: usize = 16 * 1024;
+
+/// Length of TLS record header
+pub(crate) const RECORD_HEADER_LEN: usize = 5;
+
+/// Encode a fatal alert as an unencrypted record.  This is only valid
+/// before the first protected record has been sent, i.e. before
+/// anything but a plain-text alert has gone out in response to the
+/// ClientHello.
+pub(crate) fn plaintext_alert(description: rustls::AlertDescription) -> [u8; 7] {
+    [0x15, 0x03, 0x03, 0x00, 0x02, 0x02, u8::from(description)]
+}
+
+/// Get the length of the first TLS record in `data`, including the
+/// header, limited to the length of `data`.  If the header is
+/// incomplete, returns the length of `data`.
+pub(crate) fn first_record_len(data: &[u8]) -> usize {
+    if data.len() < RECORD_HEADER_LEN {
+        return data.len();
+    }
+    let len = RECORD_HEADER_LEN + usize::from(u16::from_be_bytes([data[3], data[4]]));
+    len.min(data.len())
+}
+
+/// Tracks TLS record headers in the incoming byte stream, to check
+/// record lengths against a limit before Rustls sees them
+#[derive(Clone, Copy)]
+pub(crate) struct RecordLimit {
+    limit: usize,
+    header: [u8; RECORD_HEADER_LEN],
+    header_len: usize,
+    body_left: usize,
+}
+
+impl RecordLimit {
+    pub(crate) fn new(limit: usize) -> Self {
+        Self {
+            limit,
+            header: [0; RECORD_HEADER_LEN],
+            header_len: 0,
+            body_left: 0,
+        }
+    }
+
+    /// Advance through the next section of the byte stream, failing
+    /// if a record header is found for a record that is too long.  To
+    /// check data without advancing, scan a copy.
+    pub(crate) fn scan(&mut self, mut data: &[u8]) -> Result<(), TlsError> {
+        while !data.is_empty() {
+            if self.body_left > 0 {
+                let count = self.body_left.min(data.len());
+                self.body_left -= count;
+                data = &data[count..];
+                continue;
+            }
+            self.header[self.header_len] = data[0];
+            self.header_len += 1;
+            data = &data[1..];
+            if self.header_len == RECORD_HEADER_LEN {
+                let len = usize::from(u16::from_be_bytes([self.header[3], self.header[4]]));
+                if len > self.limit {
+                    return Err(TlsError::Check(format!(
+                        "Incoming TLS record of {len} bytes exceeds limit of {} bytes",
+                        self.limit
+                    )));
+                }
+                self.header_len = 0;
+                self.body_left = len;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// TLS record content type for ChangeCipherSpec
+const CONTENT_CHANGE_CIPHER_SPEC: u8 = 20;
+
+/// TLS record content type for handshake messages
+const CONTENT_HANDSHAKE: u8 = 22;
+
+/// Length of a handshake message header: type and 24-bit length
+const HANDSHAKE_HEADER_LEN: usize = 4;
+
+/// Tracks the handshake messages in one direction of the TLS byte
+/// stream, to report the type and length of each as its header
+/// passes through.  Only handshake records that are not encrypted
+/// can be parsed, so this stops at the first ChangeCipherSpec record.
+#[derive(Clone, Copy, Default)]
+struct HandshakeScan {
+    header: [u8; RECORD_HEADER_LEN],
+    header_len: usize,
+    content_type: u8,
+    body_left: usize,
+    msg_header: [u8; HANDSHAKE_HEADER_LEN],
+    msg_header_len: usize,
+    msg_left: usize,
+    stopped: bool,
+}
+
+impl HandshakeScan {
+    /// Advance through the next section of the byte stream
+    fn scan(&mut self, mut data: &[u8], report: &mut dyn FnMut(u8, usize)) {
+        while !data.is_empty() && !self.stopped {
+            if self.body_left > 0 {
+                let count = self.body_left.min(data.len());
+                if self.content_type == CONTENT_HANDSHAKE {
+                    self.scan_messages(&data[..count], report);
+                }
+                self.body_left -= count;
+                data = &data[count..];
+                continue;
+            }
+            self.header[self.header_len] = data[0];
+            self.header_len += 1;
+            data = &data[1..];
+            if self.header_len == RECORD_HEADER_LEN {
+                self.header_len = 0;
+                self.content_type = self.header[0];
+                self.body_left = usize::from(u16::from_be_bytes([self.header[3], self.header[4]]));
+                self.stopped = self.content_type == CONTENT_CHANGE_CIPHER_SPEC;
+            }
+        }
+    }
+
+    /// Advance through handshake record contents.  Messages may be
+    /// split across records.
+    fn scan_messages(&mut self, mut data: &[u8], report: &mut dyn FnMut(u8, usize)) {
+        while !data.is_empty() {
+            if self.msg_left > 0 {
+                let count = self.msg_left.min(data.len());
+                self.msg_left -= count;
+                data = &data[count..];
+                continue;
+            }
+            self.msg_header[self.msg_header_len] = data[0];
+            self.msg_header_len += 1;
+            data = &data[1..];
+            if self.msg_header_len == HANDSHAKE_HEADER_LEN {
+                let [msg_type, l0, l1, l2] = self.msg_header;
+                let len = usize::from(l0) << 16 | usize::from(l1) << 8 | usize::from(l2);
+                report(msg_type, len);
+                self.msg_header_len = 0;
+                self.msg_left = len;
+            }
+        }
+    }
+}
+
+/// Reports the handshake messages passing through `ext` in both
+/// directions to a callback, for `on_handshake_message`
+pub(crate) struct HandshakeWatch {
+    callback: Box<dyn FnMut(u8, usize) + Send>,
+    incoming: HandshakeScan,
+    outgoing: HandshakeScan,
+}
+
+impl HandshakeWatch {
+    pub(crate) fn new(callback: Box<dyn FnMut(u8, usize) + Send>) -> Self {
+        Self {
+            callback,
+            incoming: HandshakeScan::default(),
+            outgoing: HandshakeScan::default(),
+        }
+    }
+
+    /// Scan the next section of TLS data sent (`outgoing` true) or
+    /// received
+    pub(crate) fn observe(&mut self, outgoing: bool, data: &[u8]) {
+        let scan = if outgoing {
+            &mut self.outgoing
+        } else {
+            &mut self.incoming
+        };
+        scan.scan(data, &mut *self.callback);
+    }
+}
@@ -1,7 +1,16 @@
-use crate::TlsError;
-use pipebuf::{tripwire, PBufRdWr};
-use rustls::{ServerConfig, ServerConnection};
-use std::io::ErrorKind;
+use crate::capture::{Extras, Tee};
+use crate::record::{self, HandshakeWatch, RecordLimit};
+use crate::{
+    Clock, CloseAction, CloseReason, ConnectionDescriptor, CryptoSummary, Progress,
+    ShutdownProgress, Termination, TlsError,
+};
+use pipebuf::{tripwire, PBufRdWr, PBufWr};
+use rustls::pki_types::{CertificateDer, UnixTime};
+use rustls::server::{AcceptedAlert, Acceptor, ClientHello};
+use rustls::{
+    AlertDescription, ProtocolVersion, ServerConfig, ServerConnection, SupportedCipherSuite,
+};
+use std::io::{BufRead, ErrorKind, Read, Write};
 use std::sync::Arc;
 
 /// [`PipeBuf`] wrapper of [**Rustls**] [`ServerConnection`]
@@ -16,6 +25,41 @@ use std::sync::Arc;
 /// [**Rustls**]: https://crates.io/crates/rustls
 pub struct TlsServer {
     sc: Option<ServerConnection>,
+    transport_failed: bool,
+    recent_errors: Vec<TlsError>,
+    outbound_termination: Option<Termination>,
+    close_reason: Option<CloseReason>,
+    started: bool,
+    read_closed: bool,
+    write_closed: bool,
+    single_record: bool,
+    draining: bool,
+    close_notify_sent: bool,
+    close_notify_received: bool,
+    prebuffered: Vec<u8>,
+    coalesce_max: u32,
+    coalesce_held: u32,
+    quota_remaining: Option<u64>,
+    close_transport: bool,
+    max_plaintext_buffered: usize,
+    alert_on_error: bool,
+    clean_close_action: CloseAction,
+    abort_action: CloseAction,
+    refresh_threshold: u64,
+    bytes_since_refresh: u64,
+    borrowed_len: usize,
+    plaintext_waiting: usize,
+    max_iterations: u32,
+    flight_sent: bool,
+    round_trips: u8,
+    would_block_count: u64,
+    extras: Option<Box<Extras>>,
+    paused: bool,
+    plaintext_held: bool,
+    half_rtt: bool,
+    half_rtt_bytes: u64,
+    require_sni: bool,
+    accept: Option<Accept>,
 }
 
 impl TlsServer {
@@ -23,13 +67,173 @@ impl TlsServer {
     /// or set it up to just pass data straight through if there is no
     /// configuration provided
     pub fn new(config: Option<Arc<ServerConfig>>) -> Result<Self, rustls::Error> {
+        let half_rtt = config.as_ref().is_some_and(|conf| conf.send_half_rtt_data);
         let sc = if let Some(conf) = config {
             Some(ServerConnection::new(conf)?)
         } else {
             None
         };
 
-        Ok(Self { sc })
+        Ok(Self {
+            sc,
+            half_rtt,
+            ..Self::blank()
+        })
+    }
+
+    /// Create a new TLS engine that waits for the ClientHello, and
+    /// then calls `select` to choose the configuration to use for the
+    /// connection.  The parsed ClientHello gives access to the SNI
+    /// server name, the offered ALPN protocols and so on, so that for
+    /// example a different certificate profile can be used for `h2`
+    /// than for `http/1.1`.
+    pub fn new_with_selector(
+        select: impl FnMut(&ClientHello<'_>) -> Arc<ServerConfig> + Send + 'static,
+    ) -> Self {
+        Self {
+            accept: Some(Accept {
+                acceptor: Acceptor::default(),
+                select: Box::new(select),
+            }),
+            ..Self::blank()
+        }
+    }
+
+    /// Wrapper with TLS disabled and all settings at their defaults
+    fn blank() -> Self {
+        Self {
+            sc: None,
+            transport_failed: false,
+            recent_errors: Vec::new(),
+            outbound_termination: None,
+            close_reason: None,
+            started: false,
+            read_closed: false,
+            write_closed: false,
+            single_record: false,
+            draining: false,
+            close_notify_sent: false,
+            close_notify_received: false,
+            prebuffered: Vec::new(),
+            coalesce_max: 0,
+            coalesce_held: 0,
+            quota_remaining: None,
+            close_transport: true,
+            max_plaintext_buffered: usize::MAX,
+            alert_on_error: true,
+            clean_close_action: CloseAction::CloseNotify,
+            abort_action: CloseAction::Abort,
+            refresh_threshold: crate::DEFAULT_KEY_REFRESH_BYTES,
+            bytes_since_refresh: 0,
+            borrowed_len: 0,
+            plaintext_waiting: 0,
+            max_iterations: crate::DEFAULT_MAX_ITERATIONS,
+            flight_sent: false,
+            round_trips: 0,
+            would_block_count: 0,
+            extras: None,
+            paused: false,
+            plaintext_held: false,
+            half_rtt: false,
+            half_rtt_bytes: 0,
+            require_sni: false,
+            accept: None,
+        }
+    }
+
+    /// Create a new TLS engine using the given Rustls configuration,
+    /// but with the ALPN protocol chosen by the `select` callback
+    /// instead of from the configuration's `alpn_protocols` list.
+    /// Once the ClientHello has arrived, `select` is called with the
+    /// list of protocols offered by the client (empty if none), and
+    /// returns the protocol to use, or `None` to not negotiate one.
+    pub fn new_with_alpn_selector(
+        config: Arc<ServerConfig>,
+        select: impl Fn(&[&[u8]]) -> Option<Vec<u8>> + Send + 'static,
+    ) -> Self {
+        Self::new_with_selector(move |hello| {
+            let offered: Vec<&[u8]> = hello
+                .alpn()
+                .map(|protocols| protocols.collect())
+                .unwrap_or_default();
+            let mut config = (*config).clone();
+            config.alpn_protocols = select(&offered).into_iter().collect();
+            Arc::new(config)
+        })
+    }
+
+    /// Create a new TLS engine as for [`TlsServer::new`], but with some
+    /// bytes that have already been read from the transport, for
+    /// example when upgrading a connection STARTTLS-style after
+    /// protocol sniffing.  These bytes are treated as coming before
+    /// anything that arrives in `ext.rd`.
+    pub fn new_with_prebuffered(
+        config: Option<Arc<ServerConfig>>,
+        prebuffered: &[u8],
+    ) -> Result<Self, rustls::Error> {
+        let mut this = Self::new(config)?;
+        this.prebuffered = prebuffered.to_vec();
+        Ok(this)
+    }
+
+    /// Switch a wrapper created without a configuration over to TLS,
+    /// STARTTLS-style.  Calls to [`TlsServer::process`] after this perform
+    /// the TLS handshake and then encrypt and decrypt data as normal.
+    /// Passthrough mode holds no data internally, so nothing is lost
+    /// at the switch-over, but the caller must make sure that the
+    /// plain-text protocol exchange has completed before switching,
+    /// i.e. that no more plain-text is expected from the peer.
+    ///
+    /// Fails if TLS is already active, or if Rustls rejects the
+    /// configuration.
+    pub fn activate_tls(&mut self, config: Arc<ServerConfig>) -> Result<(), rustls::Error> {
+        if self.sc.is_some() {
+            return Err(rustls::Error::General("TLS is already active".into()));
+        }
+        let half_rtt = config.send_half_rtt_data;
+        self.sc = Some(ServerConnection::new(config)?);
+        self.half_rtt = half_rtt;
+        Ok(())
+    }
+
+    /// Check that a configuration is accepted by Rustls, by doing a
+    /// trial construction of a connection and then discarding it.
+    /// This allows configurations to be checked at startup rather
+    /// than when the first connection is made.
+    pub fn validate_config(config: Arc<ServerConfig>) -> Result<(), rustls::Error> {
+        ServerConnection::new(config).map(|_| ())
+    }
+
+    /// Create a new TLS engine as for [`TlsServer::new`], but with Rustls
+    /// getting the current time from `clock`, for example to make
+    /// certificate validity and ticket ages deterministic in tests.
+    /// This clones the configuration to replace its `time_provider`,
+    /// so for many connections it is cheaper to set the
+    /// `time_provider` on a shared configuration instead.
+    pub fn new_with_clock(
+        config: Option<Arc<ServerConfig>>,
+        clock: fn() -> UnixTime,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|conf| {
+            let mut conf = (*conf).clone();
+            conf.time_provider = Arc::new(Clock(clock));
+            Arc::new(conf)
+        }))
+    }
+
+    /// Create a new TLS engine as for [`TlsServer::new`], but accepting
+    /// the given ALPN protocols for this connection, in order of
+    /// preference, instead of the configuration's `alpn_protocols`
+    /// list.  This clones the configuration to make the change.
+    pub fn new_with_alpn_protocols(
+        config: Option<Arc<ServerConfig>>,
+        protocols: Vec<Vec<u8>>,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|conf| {
+            let mut conf = (*conf).clone();
+            conf.alpn_protocols = protocols;
+            Arc::new(conf)
+        }))
     }
 
     /// Get immutable access to the wrapped `ServerConnection`, if
@@ -38,6 +242,779 @@ impl TlsServer {
         self.sc.as_ref()
     }
 
+    /// Get mutable access to the wrapped `ServerConnection`, if
+    /// available, e.g. to call `send_close_notify` or use the
+    /// `writer` or `reader` directly.  Take care, since anything
+    /// done to the connection behind the wrapper's back may upset
+    /// [`TlsServer::process`].  Any chunk returned by
+    /// [`TlsServer::process_read_borrowed`] counts as consumed.
+    pub fn connection_mut(&mut self) -> Option<&mut ServerConnection> {
+        self.release_borrowed();
+        self.sc.as_mut()
+    }
+
+    /// Indicate that the transport has failed, e.g. a read or write
+    /// on the underlying socket returned an `io::Error`.  Aborts
+    /// `ext.wr` immediately, and on the next call to
+    /// [`TlsServer::process`] any pending data is discarded and `int.wr`
+    /// is aborted, so that the internal handlers see an "Aborting"
+    /// end-of-file.  The wrapper passes no more data after this call.
+    pub fn transport_error(&mut self, mut ext: PBufRdWr) {
+        self.transport_failed = true;
+        self.close_reason.get_or_insert(CloseReason::TransportError);
+        if !ext.wr.is_eof() {
+            ext.wr.abort();
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        }
+    }
+
+    /// Enable or disable single-record mode.  When enabled, at most
+    /// one TLS record's worth of plain-text is in flight at a time in
+    /// each direction, and each record is pushed onward as soon as it
+    /// is available (`ext.wr` for outgoing, `int.wr` for incoming).
+    /// Outgoing plain-text is passed to Rustls one record at a time,
+    /// and incoming plain-text is passed to `int.wr` one record at a
+    /// time, each only once the previous one has been consumed.  This
+    /// keeps latency to a minimum for interactive protocols at the
+    /// cost of some throughput for bulk transfers.
+    pub fn set_single_record_in_flight(&mut self, enabled: bool) {
+        self.single_record = enabled;
+    }
+
+    /// Get the IANA name of the negotiated cipher suite, for example
+    /// `"TLS13_AES_128_GCM_SHA256"`, suitable for use as a metrics
+    /// label.  Returns `None` if TLS is disabled, or if the cipher
+    /// suite has not been negotiated yet.
+    pub fn cipher_suite_name(&self) -> Option<&'static str> {
+        self.sc
+            .as_ref()
+            .and_then(|sc| sc.negotiated_cipher_suite())
+            .and_then(|suite| suite.suite().as_str())
+    }
+
+    /// Begin draining the connection.  Subsequent calls to
+    /// [`TlsServer::process`] ignore any new data in `int.rd`,
+    /// leaving it for the caller to handle, but still flush out any
+    /// plain-text already accepted by Rustls, and then close the TLS
+    /// stream cleanly with a `close_notify`.  The incoming direction
+    /// is not affected.
+    pub fn begin_drain(&mut self) {
+        self.draining = true;
+    }
+
+    /// Get the negotiated protocol version, e.g. for logging.
+    /// Returns `None` if TLS is disabled or the handshake has not
+    /// completed yet.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        let sc = self.sc.as_ref().filter(|sc| !sc.is_handshaking())?;
+        sc.protocol_version()
+    }
+
+    /// Get the negotiated cipher suite, e.g. for compliance checks.
+    /// Returns `None` if TLS is disabled or the handshake has not
+    /// completed yet.
+    pub fn negotiated_cipher_suite(&self) -> Option<SupportedCipherSuite> {
+        let sc = self.sc.as_ref().filter(|sc| !sc.is_handshaking())?;
+        sc.negotiated_cipher_suite()
+    }
+
+    /// Check whether the TLS handshake has completed.  Returns
+    /// `false` if TLS is disabled.  To act exactly once on
+    /// completion, see [`TlsServer::process_report`].
+    pub fn handshake_complete(&self) -> bool {
+        self.sc.as_ref().is_some_and(|sc| !sc.is_handshaking())
+    }
+
+    /// Check whether TLS 1.3 was negotiated.  Returns `false` if TLS
+    /// is disabled or the version has not been negotiated yet.
+    pub fn is_tls13(&self) -> bool {
+        self.sc.as_ref().and_then(|sc| sc.protocol_version()) == Some(ProtocolVersion::TLSv1_3)
+    }
+
+    /// Set the maximum length of the payload of an incoming TLS
+    /// record.  Record headers are checked as data is passed from
+    /// `ext.rd` to Rustls, and [`TlsServer::process`] fails if a peer
+    /// sends a longer record.  This limits the memory that a peer can
+    /// make us commit when only small records are expected.  Note
+    /// that the payload includes TLS overheads such as the AEAD tag,
+    /// and that the handshake must fit within the limit too.
+    ///
+    /// Record headers can only be found by following the stream from
+    /// the start, so this must be called before [`TlsServer::process`]
+    /// has done anything, and fails otherwise.
+    pub fn set_max_accepted_record_size(&mut self, bytes: usize) -> Result<(), TlsError> {
+        if self.started {
+            return Err(TlsError::Usage(
+                "Record size limit must be set before processing starts".into(),
+            ));
+        }
+        self.extras().record_limit = Some(RecordLimit::new(bytes));
+        Ok(())
+    }
+
+    /// Get a 32-byte value bound to the handshake transcript, for use
+    /// in channel-binding schemes.  Rustls doesn't expose the raw
+    /// transcript hash, so this is the RFC 9266 `tls-exporter`
+    /// channel binding: 32 bytes of exported keying material with
+    /// label `EXPORTER-Channel-Binding` and no context.  The exporter
+    /// secret is derived from the handshake transcript, so both ends
+    /// get the same value only if they saw the same handshake.
+    ///
+    /// Returns `None` if TLS is disabled, the handshake is not yet
+    /// complete, or the connection is not TLS 1.3, since RFC 9266
+    /// only defines this for TLS 1.3.
+    pub fn transcript_hash(&self) -> Option<Vec<u8>> {
+        let sc = self.sc.as_ref()?;
+        if sc.is_handshaking() || !self.is_tls13() {
+            return None;
+        }
+        sc.export_keying_material(vec![0; 32], b"EXPORTER-Channel-Binding", None)
+            .ok()
+    }
+
+    /// Fill `out` with keying material derived from the connection
+    /// secrets, as described in RFC 5705, using `label` and optional
+    /// `context`, e.g. for token binding.  Unlike calling Rustls
+    /// directly, invalid arguments are rejected with a descriptive
+    /// error: the handshake must be complete, `out` must not be empty
+    /// or exceed the TLS 1.3 limit of 255 hash blocks, and `label`
+    /// must not be empty.  Fails if TLS is disabled.  (buffered only)
+    pub fn export_keying_material(
+        &self,
+        out: &mut [u8],
+        label: &[u8],
+        context: Option<&[u8]>,
+    ) -> Result<(), TlsError> {
+        let sc = match self.sc {
+            Some(ref sc) => sc,
+            None if self.accept.is_some() => {
+                return Err(TlsError::Usage(
+                    "Can't export keying material before the handshake is complete".into(),
+                ))
+            }
+            None => {
+                return Err(TlsError::Usage(
+                    "Can't export keying material: TLS is disabled".into(),
+                ))
+            }
+        };
+        crate::check_export(sc, out.len(), label)?;
+        sc.export_keying_material(out, label, context)
+            .map_err(|e| TlsError::rustls("Failed to export keying material", e))?;
+        Ok(())
+    }
+
+    /// Indicate that the transport has seen the peer vanish without a
+    /// clean shutdown, e.g. a connection reset.  Unlike
+    /// [`TlsServer::transport_error`], this takes effect immediately:
+    /// pending data is discarded and both `ext.wr` and `int.wr` are
+    /// aborted, so that the internal handlers see an "Aborting"
+    /// end-of-file straight away.  The wrapper passes no more data
+    /// after this call.
+    pub fn notify_peer_reset(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) {
+        self.transport_failed = true;
+        self.close_reason.get_or_insert(CloseReason::RemoteReset);
+        self.read_closed = true;
+        self.write_closed = true;
+        crate::abort_all(&mut ext, &mut int);
+        self.outbound_termination.get_or_insert(Termination::Abort);
+    }
+
+    /// Check whether the incoming plain-text stream has ended, i.e.
+    /// whether `int.wr` has been closed or aborted
+    pub fn read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Check whether the outgoing plain-text stream has ended, i.e.
+    /// whether end-of-file from `int.rd` has been handled, or `ext.wr`
+    /// has been closed or aborted.  This together with
+    /// [`TlsServer::read_closed`] makes half-closed states observable.
+    pub fn write_closed(&self) -> bool {
+        self.write_closed
+    }
+
+    /// Get the SNI server name that the client requested, e.g. for
+    /// routing in virtual-hosting setups.  This is available as soon
+    /// as a [`TlsServer::process`] call has handled the ClientHello,
+    /// before the handshake completes.  Returns `None` if TLS is
+    /// disabled, if the ClientHello hasn't been handled yet, or if
+    /// the client didn't send SNI.  (buffered only)
+    pub fn server_name(&self) -> Option<&str> {
+        self.sc.as_ref().and_then(|sc| sc.server_name())
+    }
+
+    /// Check whether the transport can be half-closed, e.g. with
+    /// `shutdown(Write)` on a TCP socket.  This is `true` once our
+    /// `close_notify` has been flushed to `ext.wr` whilst incoming
+    /// data is still expected, i.e. before the peer has closed its
+    /// side.
+    pub fn wants_transport_half_close(&self) -> bool {
+        self.close_notify_sent
+            && !self.sc.as_ref().is_some_and(|sc| sc.wants_write())
+            && !self.read_closed
+            && !self.close_notify_received
+    }
+
+    /// Get the negotiated ALPN protocol.  Returns `None` if TLS is
+    /// disabled, if the handshake has not got far enough yet, or if
+    /// no protocol was agreed.  The result is only meaningful once
+    /// the handshake is complete, i.e. once `is_handshaking()` on the
+    /// connection returns `false`, so a `None` before then doesn't
+    /// mean that no protocol will be agreed.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.sc.as_ref().and_then(|sc| sc.alpn_protocol())
+    }
+
+    /// Get the name of the negotiated key exchange group, for example
+    /// `"X25519"` or `"X25519MLKEM768"`, suitable for use as a
+    /// metrics label.  Returns `None` if TLS is disabled, or if key
+    /// exchange has not completed yet.
+    pub fn key_exchange_group_str(&self) -> Option<&'static str> {
+        self.sc
+            .as_ref()
+            .and_then(|sc| sc.negotiated_key_exchange_group())
+            .and_then(|group| group.name().as_str())
+    }
+
+    /// Get a summary of the negotiated cryptographic parameters, e.g.
+    /// for a compliance report.  Returns `None` if TLS is disabled or
+    /// the handshake has not completed yet.
+    pub fn crypto_summary(&self) -> Option<CryptoSummary> {
+        let sc = self.sc.as_ref()?;
+        if sc.is_handshaking() {
+            return None;
+        }
+        Some(CryptoSummary {
+            version: sc.protocol_version()?,
+            cipher_suite: sc.negotiated_cipher_suite()?.suite(),
+            kx_group: sc.negotiated_key_exchange_group().map(|group| group.name()),
+        })
+    }
+
+    /// Get the most recent errors returned by [`TlsServer::process`],
+    /// oldest first, for diagnostics when one failure leads to
+    /// others.  A failure to send the alert for an error is kept too,
+    /// just before the error itself.  Only the last few errors are
+    /// kept.
+    pub fn recent_errors(&self) -> &[TlsError] {
+        &self.recent_errors
+    }
+
+    /// Check how this wrapper ended the outgoing stream on `ext.wr`,
+    /// e.g. to decide whether to shut down a TCP connection with a
+    /// FIN or to reset it.  Returns `None` if `ext.wr` has not been
+    /// closed or aborted by the wrapper yet.
+    pub fn outbound_termination(&self) -> Option<Termination> {
+        self.outbound_termination
+    }
+
+    /// Get the reason the connection was ended by the transport, if
+    /// [`TlsServer::transport_error`] or [`TlsServer::notify_peer_reset`] has
+    /// been called.  The first reason reported is kept.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    /// Get the certificate chain presented by the peer, end-entity
+    /// certificate first, e.g. for authorization decisions.  Returns
+    /// `None` if TLS is disabled or if the peer hasn't presented a
+    /// certificate (yet).
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.sc.as_ref()?.peer_certificates()
+    }
+
+    /// Get the end of the validity period ("notAfter") of the
+    /// peer's end-entity certificate, e.g. to monitor for
+    /// certificates that will soon expire.  Returns `None` if TLS is
+    /// disabled, if the peer hasn't presented a certificate (yet),
+    /// or if it can't be parsed.
+    pub fn peer_cert_not_after(&self) -> Option<UnixTime> {
+        let cert = self.sc.as_ref()?.peer_certificates()?.first()?;
+        crate::cert::not_after(cert)
+    }
+
+    /// Get a summary of this wrapper's mode and negotiated
+    /// parameters, for logging or serialization
+    pub fn descriptor(&self) -> ConnectionDescriptor {
+        let sc = self.sc.as_ref();
+        ConnectionDescriptor {
+            server: true,
+            tls: sc.is_some() || self.accept.is_some(),
+            protocol_version: sc
+                .and_then(|sc| sc.protocol_version())
+                .and_then(|version| version.as_str())
+                .map(String::from),
+            cipher_suite: self.cipher_suite_name().map(String::from),
+            alpn_protocol: sc.and_then(|sc| sc.alpn_protocol()).map(|p| p.to_vec()),
+        }
+    }
+
+    /// Abort the connection, but only after encrypting and writing
+    /// out to `ext.wr` all the plain-text already committed to
+    /// `int.rd`.  This does a final [`TlsServer::process`] call, and then
+    /// discards any remaining data and aborts both `ext.wr` and
+    /// `int.wr` as for [`TlsServer::notify_peer_reset`].  Returns `Ok(true)`
+    /// if all the plain-text was flushed before the abort, or
+    /// `Ok(false)` if some was lost, e.g. because the handshake had
+    /// not yet completed.
+    pub fn abort_after_flush(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<bool, TlsError> {
+        self.process(ext.reborrow(), int.reborrow())?;
+        let flushed = int.rd.is_empty()
+            && match self.sc {
+                Some(ref sc) => !sc.is_handshaking() && !sc.wants_write(),
+                None => true,
+            };
+        self.notify_peer_reset(ext, int);
+        Ok(flushed)
+    }
+
+    /// Check that the connection completed cleanly, for use after a
+    /// correctness-critical transfer.  Both directions must have been
+    /// closed, and with TLS enabled, a `close_notify` must have been
+    /// both sent and received, and Rustls must have no TLS data left
+    /// waiting to be written to `ext.wr`.  Returns an error
+    /// describing the first problem found.
+    pub fn verify_complete(&self) -> Result<(), TlsError> {
+        if !self.read_closed || !self.write_closed {
+            return Err(TlsError::Check(
+                "Connection has not been closed in both directions".into(),
+            ));
+        }
+        if let Some(ref sc) = self.sc {
+            if sc.wants_write() {
+                return Err(TlsError::Check(
+                    "TLS data is still waiting to be written to ext.wr".into(),
+                ));
+            }
+            if !self.close_notify_sent {
+                return Err(TlsError::Check(
+                    "Outgoing TLS stream was not closed with close_notify".into(),
+                ));
+            }
+            if !self.close_notify_received {
+                return Err(TlsError::Check(
+                    "Incoming TLS stream was not closed with close_notify".into(),
+                ));
+            }
+        }
+        Ok(())
+    }
+
+    /// Send `buf` ahead of any plain-text still waiting in `int.rd`.
+    /// The data is passed straight to Rustls, and so goes out in the
+    /// next record written to `ext.wr` on the following call to
+    /// [`TlsServer::process`].  Records already encrypted by Rustls can't
+    /// be reordered, so this only jumps ahead of data that
+    /// [`TlsServer::process`] has not yet taken from `int.rd`.  If the
+    /// handshake is still in progress, the data is sent as soon as
+    /// it completes.  Returns the number of bytes accepted, which is
+    /// less than `buf.len()` only if a limit set with
+    /// [`TlsServer::set_buffer_limit`] has been reached, in which case the
+    /// rest should be passed again once `process` has written some
+    /// out.  Fails if TLS is disabled, or if a `close_notify` has
+    /// already been sent.
+    pub fn write_urgent(&mut self, buf: &[u8]) -> Result<usize, TlsError> {
+        if self.close_notify_sent {
+            return Err(TlsError::Usage(
+                "Urgent write attempted after close_notify was sent".into(),
+            ));
+        }
+        match self.sc {
+            Some(ref mut sc) => sc.writer().write(buf).map_err(|e| {
+                TlsError::Internal(format!(
+                    "Unexpected error from ServerConnection::writer.write: {e}"
+                ))
+            }),
+            None => Err(TlsError::Usage(
+                "Urgent write requires TLS to be active".into(),
+            )),
+        }
+    }
+
+    /// Drive a clean shutdown of the connection.  Call this instead
+    /// of [`TlsServer::process`] once shutdown has been decided.  This
+    /// starts draining as for [`TlsServer::begin_drain`], so any new data
+    /// in `int.rd` is ignored, then processes as usual, reporting how
+    /// far the close has progressed.  Our `close_notify` is sent once
+    /// all accepted plain-text has been encrypted, and then incoming
+    /// data continues to be passed to `int.wr` until the peer closes
+    /// its side.  Once [`ShutdownProgress::Done`] is returned, the
+    /// transport can be closed.
+    ///
+    /// [`ShutdownProgress::Done`]: crate::ShutdownProgress::Done
+    pub fn drive_shutdown(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<ShutdownProgress, TlsError> {
+        self.draining = true;
+        self.process(ext.reborrow(), int.reborrow())?;
+        let flushed = ext.wr.is_eof()
+            || (self.close_notify_sent && !self.sc.as_ref().is_some_and(|sc| sc.wants_write()));
+        Ok(if !flushed {
+            ShutdownProgress::Flushing
+        } else if !self.read_closed && !self.close_notify_received {
+            ShutdownProgress::AwaitingPeerClose
+        } else {
+            ShutdownProgress::Done
+        })
+    }
+
+    /// Coalesce small writes into fewer TLS records, similar to
+    /// Nagle's algorithm.  When less than a full record of
+    /// plain-text is waiting in `int.rd`, it is left there for up to
+    /// `max_delay_iterations` calls to [`TlsServer::process`] to give the
+    /// protocol handler a chance to add more, reducing both overhead
+    /// and the information leaked by record sizes.  A call that holds
+    /// data back returns `Ok(true)`, so the usual loop calling
+    /// [`TlsServer::process`] until it reports no activity sends the data
+    /// once the delay has run out, without needing a timer.  A
+    /// pending end-of-file flushes immediately.  Set to 0 (the
+    /// default) to disable.
+    pub fn set_coalesce(&mut self, max_delay_iterations: u32) {
+        self.coalesce_max = max_delay_iterations;
+        self.coalesce_held = 0;
+    }
+
+    /// Limit the total plain-text that will be accepted from `int.rd`
+    /// and sent to the peer, counting from this call, e.g. for quota
+    /// enforcement.  Once `bytes` have been sent, the connection
+    /// starts draining as for [`TlsServer::begin_drain`]: anything more in
+    /// `int.rd` is left for the caller, and the outgoing TLS stream
+    /// is closed cleanly with a `close_notify`.  Incoming data is not
+    /// counted or limited.
+    pub fn set_plaintext_quota(&mut self, bytes: u64) {
+        self.quota_remaining = Some(bytes);
+        if bytes == 0 {
+            self.draining = true;
+        }
+    }
+
+    /// Control whether `ext.wr` is closed once the TLS stream has
+    /// ended cleanly.  When enabled (the default), `ext.wr` is
+    /// closed after the final `close_notify` has been written.  When
+    /// disabled, the `close_notify` is still sent, but `ext.wr` is
+    /// left open so that the caller can manage the transport, e.g.
+    /// for a multiplexed transport that outlives the TLS connection.
+    /// Aborts are still passed through to `ext.wr`.
+    pub fn set_manage_transport_close(&mut self, enabled: bool) {
+        self.close_transport = enabled;
+    }
+
+    /// Change what is sent to the peer when the internal side ends
+    /// `int.rd`, e.g. for a gateway translating between protocols
+    /// with different close semantics.  `clean_to` applies to a
+    /// clean close of `int.rd`, and `abort_to` to an abort.  The
+    /// default is [`CloseAction::CloseNotify`] for a clean close and
+    /// [`CloseAction::Abort`] for an abort.  This has no effect if TLS
+    /// is disabled, in which case the end of `int.rd` is passed
+    /// through unchanged.  (buffered only)
+    pub fn set_internal_close_mapping(&mut self, clean_to: CloseAction, abort_to: CloseAction) {
+        self.clean_close_action = clean_to;
+        self.abort_action = abort_to;
+    }
+
+    /// Control whether a fatal alert is sent to the peer when a TLS
+    /// error occurs.  When enabled (the default), the alert that
+    /// Rustls generates is written to `ext.wr` before the error is
+    /// returned, as for Rustls.  When disabled, no alert is sent and
+    /// `ext.wr` is aborted instead, so that the peer learns nothing
+    /// about the reason, e.g. to avoid fingerprinting.  (buffered
+    /// only)
+    pub fn set_send_alert_on_error(&mut self, enabled: bool) {
+        self.alert_on_error = enabled;
+    }
+
+    /// Set the limit on the plain-text and TLS data that Rustls will
+    /// buffer internally, as for Rustls `set_buffer_limit`.  If the
+    /// limit is reached, data is left in `int.rd` until Rustls has
+    /// space again.  Has no effect if the connection has not been
+    /// created yet, i.e. if TLS is disabled or a server is still
+    /// waiting for the ClientHello.
+    pub fn set_buffer_limit(&mut self, limit: Option<usize>) {
+        if let Some(ref mut sc) = self.sc {
+            sc.set_buffer_limit(limit);
+        }
+    }
+
+    /// Set a high-watermark for the decrypted plain-text waiting in
+    /// `int.wr`, e.g. to stop a fast sender from growing `int.wr`
+    /// without bound whilst the internal side is slow to consume it.
+    /// Once `int.wr` holds more than `bytes`, any more plain-text is
+    /// held in Rustls, and TLS data is left in `ext.rd`, until the
+    /// internal side has consumed enough.  No more than `bytes` is
+    /// passed on at a time, so `int.wr` never holds more than twice
+    /// `bytes`.  Held data doesn't count as activity, so a loop on
+    /// the return value of [`TlsServer::process`] stops rather than
+    /// growing `int.wr`.  The minimum is 1 byte.  The default is no
+    /// limit.  (buffered only)
+    pub fn set_max_plaintext_buffered(&mut self, bytes: usize) {
+        self.max_plaintext_buffered = bytes.max(1);
+    }
+
+    /// Remove the limit on the data that Rustls will buffer
+    /// internally, as for Rustls `set_buffer_limit(None)`, e.g. for
+    /// maximum throughput in bulk transfers.  Be aware that Rustls
+    /// will then accept any amount of plain-text before the
+    /// handshake completes, and any amount of TLS data that can't
+    /// be written out, so memory use is bounded only by what the
+    /// internal side sends.  As for [`TlsServer::set_buffer_limit`], this
+    /// has no effect if the connection has not been created yet.
+    pub fn set_unbounded_buffering(&mut self) {
+        self.set_buffer_limit(None);
+    }
+
+    /// Pause or resume the flow of application data, e.g. for flow
+    /// control at the application layer.  While paused,
+    /// [`TlsServer::process`] neither reads from `int.rd` nor writes to
+    /// `int.wr`, including end-of-file, but still advances the
+    /// handshake and handles incoming alerts, `close_notify` and key
+    /// updates.  Incoming plain-text is held in Rustls until resumed.
+    /// Once Rustls holds 16KiB, its fixed limit, no more TLS data is
+    /// read from `ext.rd` until resumed.  (buffered only)
+    pub fn set_paused(&mut self, paused: bool) {
+        self.paused = paused;
+    }
+
+    /// Set a callback to observe the handshake messages passing
+    /// through `ext` in either direction, e.g. for a monitoring proxy
+    /// that logs handshake metadata.  It is called with the message
+    /// type (e.g. 1 for ClientHello, 2 for ServerHello) and length
+    /// as each message header is seen in the TLS record framing.
+    /// Nothing is decrypted, so only handshake messages sent in the
+    /// clear are seen.  For TLS 1.2 that is everything up to the
+    /// ChangeCipherSpec, but TLS 1.3 encrypts everything after the
+    /// ServerHello.  Nothing is reported after a ChangeCipherSpec in
+    /// that direction, so with TLS 1.3 a ClientHello sent again after
+    /// a HelloRetryRequest may be missed.  (buffered only)
+    pub fn on_handshake_message(&mut self, callback: impl FnMut(u8, usize) + Send + 'static) {
+        self.extras().watch = Some(HandshakeWatch::new(Box::new(callback)));
+    }
+
+    /// Check whether a TLS 1.3 key update is recommended, because
+    /// the plain-text sent and received since the last call to
+    /// [`TlsServer::refresh_traffic_keys`] (or since the start) has
+    /// reached the threshold.  The default threshold is 1GiB, which
+    /// is well within the AEAD usage limits.  Rustls also refreshes
+    /// keys automatically when they near those limits, so this is a
+    /// more conservative policy on top of that.  Always `false` for
+    /// TLS 1.2 or if TLS is disabled.
+    pub fn should_refresh_keys(&self) -> bool {
+        self.is_tls13() && self.bytes_since_refresh >= self.refresh_threshold
+    }
+
+    /// Set the plain-text volume in bytes after which
+    /// [`TlsServer::should_refresh_keys`] returns `true`
+    pub fn set_key_refresh_threshold(&mut self, bytes: u64) {
+        self.refresh_threshold = bytes;
+    }
+
+    /// Send a TLS 1.3 `key_update` to refresh the traffic keys, as
+    /// for Rustls `refresh_traffic_keys`.  The peer is also asked to
+    /// update its keys.  The message goes out on the next call to
+    /// [`TlsServer::process`].  Fails if TLS is disabled, if the
+    /// handshake is not yet complete, or if TLS 1.2 was negotiated.
+    pub fn refresh_traffic_keys(&mut self) -> Result<(), rustls::Error> {
+        match self.sc {
+            Some(ref mut sc) => {
+                sc.refresh_traffic_keys()?;
+                self.bytes_since_refresh = 0;
+                Ok(())
+            }
+            None => Err(rustls::Error::General("TLS is not active".into())),
+        }
+    }
+
+    /// Limit the number of iterations of the internal loop within a
+    /// single call to [`TlsServer::process`], to guard against livelock
+    /// in adversarial conditions.  If the limit is reached, the call
+    /// returns `Ok(true)` with work remaining, so the caller regains
+    /// control and should call again later.  The default is 100000,
+    /// which is far more than normal use needs.
+    pub fn set_max_iterations(&mut self, max: u32) {
+        self.max_iterations = max;
+    }
+
+    /// Get the number of times that reading decrypted data from
+    /// Rustls found it temporarily empty (`WouldBlock`) since the
+    /// wrapper was created or the count was last reset, for
+    /// diagnosing throughput problems.  Normally this stays at zero.
+    /// (buffered only)
+    pub fn would_block_count(&self) -> u64 {
+        self.would_block_count
+    }
+
+    /// Get the number of bytes of decrypted plain-text waiting in
+    /// Rustls, not yet passed to `int.wr`.  This is normally zero
+    /// after [`TlsServer::process`], but plain-text is left in Rustls
+    /// whilst paused, beyond the
+    /// [`TlsServer::set_max_plaintext_buffered`] limit, in single-record
+    /// mode, or when read with [`TlsServer::process_read_borrowed`], in
+    /// which case the chunk last returned is not counted.  TLS 1.3
+    /// early data is not counted.  Returns 0 if TLS is not active,
+    /// since data is then passed straight through.  (buffered only)
+    pub fn available_plaintext(&self) -> usize {
+        if self.sc.is_some() {
+            self.plaintext_waiting
+        } else {
+            0
+        }
+    }
+
+    /// Reset the count returned by [`TlsServer::would_block_count`]
+    pub fn reset_would_block_count(&mut self) {
+        self.would_block_count = 0;
+    }
+
+    /// Get the number of round trips the handshake took, once it has
+    /// completed, e.g. for latency analysis.  This counts the times
+    /// that data arrived from the peer after a handshake flight had
+    /// been sent.  A normal TLS 1.3 handshake takes 1, and one with
+    /// a HelloRetryRequest takes 2.  A full TLS 1.2 handshake takes 2
+    /// as seen by the client, but 1 as seen by the server, since the
+    /// server sends the final flight.  Returns `None` if TLS is
+    /// disabled or the handshake is still in progress.
+    pub fn handshake_round_trips(&self) -> Option<u8> {
+        match self.sc {
+            Some(ref sc) if !sc.is_handshaking() => Some(self.round_trips),
+            _ => None,
+        }
+    }
+
+    /// Capture a copy of all the encrypted TLS data passing through
+    /// `ext` in both directions to `writer`, with timestamps, for
+    /// offline analysis.  Each chunk is written as a frame: 1 byte
+    /// direction (0 for outgoing, 1 for incoming), 8 bytes of
+    /// microseconds since the UNIX epoch and 4 bytes of length, both
+    /// big-endian, followed by the data.  Only ciphertext is
+    /// captured, so this is safe to share, or it can be combined
+    /// with a key log to decrypt it.  Capture stops if `writer`
+    /// returns an error.  Requires the `capture` cargo feature.
+    #[cfg(feature = "capture")]
+    pub fn set_capture(&mut self, writer: Box<dyn Write + Send>) {
+        self.extras().capture.set(writer);
+    }
+
+    /// Move all the decrypted plain-text still buffered in Rustls to
+    /// `int`, returning the number of bytes moved, e.g. at teardown
+    /// after the peer has closed, to make sure that nothing is lost.
+    /// Plain-text is normally moved to `int.wr` by
+    /// [`TlsServer::process`] as soon as it is decrypted, but it may be
+    /// left in Rustls after using [`TlsServer::process_read_borrowed`].
+    /// Any chunk previously returned by that call counts as consumed.
+    /// Fails if `int` has already been closed and there is data to
+    /// move.
+    pub fn drain_remaining(&mut self, int: PBufWr) -> Result<usize, TlsError> {
+        self.drain_plaintext(int, usize::MAX, usize::MAX)
+    }
+
+    /// Move plain-text from Rustls to `int` until `int` holds more
+    /// than `watermark` bytes, moving at most `limit` bytes at a time
+    fn drain_plaintext(
+        &mut self,
+        mut int: PBufWr,
+        watermark: usize,
+        limit: usize,
+    ) -> Result<usize, TlsError> {
+        self.release_borrowed();
+        let sc = match self.sc {
+            Some(ref mut sc) => sc,
+            None => return Ok(0),
+        };
+        let mut total = 0;
+        // Any early data comes first
+        while !int.is_eof() && !int.exceeds_limit(watermark) {
+            let count = pass_early_data(sc, &mut int, limit)?;
+            if count == 0 {
+                break;
+            }
+            total += count;
+        }
+        let mut reader = sc.reader();
+        while !int.exceeds_limit(watermark) {
+            let chunk = match reader.fill_buf() {
+                Ok(chunk) => chunk,
+                // No more data, and the peer has either not closed
+                // yet or closed without a `close_notify`
+                Err(e) if matches!(e.kind(), ErrorKind::WouldBlock | ErrorKind::UnexpectedEof) => {
+                    break
+                }
+                Err(e) => return Err(TlsError::Decode(format!("TLS read error: {e}"))),
+            };
+            if chunk.is_empty() {
+                break;
+            }
+            if int.is_eof() {
+                return Err(TlsError::Usage(
+                    "Can't drain plain-text: int.wr is closed".into(),
+                ));
+            }
+            let len = chunk.len().min(limit);
+            int.append(&chunk[..len]);
+            reader.consume(len);
+            self.plaintext_waiting = self.plaintext_waiting.saturating_sub(len);
+            total += len;
+        }
+        self.bytes_since_refresh += total as u64;
+        Ok(total)
+    }
+
+    /// Check whether application data written to `int.rd` can be sent
+    /// right away.  This is true once the handshake is complete, or
+    /// for TLS 1.3 with `send_half_rtt_data` set in the
+    /// `ServerConfig`, as soon as the server's first flight has been
+    /// sent, before the client's Finished arrives (0.5-RTT data).
+    /// Note that Rustls won't send 0.5-RTT data when a client
+    /// certificate is requested, and instead holds it until the
+    /// handshake is complete.  Always `true` if TLS is disabled, and
+    /// `false` once the outgoing side has closed or the transport has
+    /// failed.
+    pub fn can_send_now(&self) -> bool {
+        if self.write_closed || self.transport_failed {
+            return false;
+        }
+        match self.sc {
+            Some(ref sc) => can_send(sc, self.half_rtt),
+            None => self.accept.is_none(),
+        }
+    }
+
+    /// Get the number of bytes of plain-text that were sent as
+    /// 0.5-RTT data, i.e. after the server's first flight but before
+    /// the client's Finished arrived, for auditing.  Such data is
+    /// sent before the client is known to be live, so has replay and
+    /// ordering implications.  See [`TlsServer::can_send_now`].
+    /// (buffered only)
+    pub fn bytes_sent_as_half_rtt(&self) -> u64 {
+        self.half_rtt_bytes
+    }
+
+    /// Check whether the wrapper can be dropped without leaving the
+    /// peer hanging.  This is true if [`TlsServer::process`] has never
+    /// done anything, if the transport has failed, or if both
+    /// directions have ended and `ext` (the `ext.wr` passed to
+    /// [`TlsServer::process`]) has been closed or aborted.
+    pub fn is_safe_to_drop(&self, ext: &PBufWr) -> bool {
+        !self.started
+            || self.transport_failed
+            || (ext.is_eof() && self.read_closed && self.write_closed)
+    }
+
+    /// Require clients to send SNI.  When enabled, a ClientHello
+    /// without a server name causes [`TlsServer::process`] to send an
+    /// `unrecognized_name` alert in place of the ServerHello, close
+    /// `ext.wr` and return an error.  If alerts are disabled with
+    /// [`TlsServer::set_send_alert_on_error`], `ext.wr` is aborted
+    /// instead.
+    pub fn set_require_sni(&mut self, required: bool) {
+        self.require_sni = required;
+    }
+
     /// Process as much data as possible, moving data between `ext`
     /// and `int`.  `ext` is the pipe which typically carries TLS
     /// protocol data to/from an external TCP connection.  `int` is
@@ -58,94 +1035,599 @@ impl TlsServer {
     /// results in an "Aborting" end-of-file.  Note that some TLS
     /// libraries always end their streams with an unclean shutdown.
     ///
+    /// If the internal side closes `int.wr` itself once the
+    /// handshake is complete, because it wants no more data, then no
+    /// more TLS data is read from `ext.rd`.  Anything further from
+    /// the peer is left unread there rather than being decrypted and
+    /// discarded.  To also close the connection towards the peer,
+    /// call [`TlsServer::begin_drain`].
+    ///
     /// Returns `Ok(true)` if there was activity, `Ok(false)` if no
     /// progress could be made, and `Err(_)` if there was an error.
     pub fn process(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) -> Result<bool, TlsError> {
-        let before = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
+        self.release_borrowed();
+        let result = self.process_inner(ext.reborrow(), int.reborrow());
+        match result {
+            Ok(activity) => {
+                self.started |= activity;
+                self.debug_check(&ext, &int);
+            }
+            Err(ref e) => crate::record_error(&mut self.recent_errors, e),
+        }
+        result
+    }
 
+    /// Make sure that everything waiting to be sent has been written
+    /// to `ext.wr` and pushed, e.g. before blocking on a long
+    /// upstream read, so that the peer isn't left waiting.  This does
+    /// a [`TlsServer::process`] call without holding back small writes
+    /// for [`TlsServer::set_coalesce`], and then writes out anything
+    /// still queued in Rustls.  Nothing is written if `ext.wr` has
+    /// been closed.
+    pub fn flush_before_block(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<(), TlsError> {
+        self.coalesce_held = self.coalesce_max;
+        self.process(ext.reborrow(), int.reborrow())?;
         if let Some(ref mut sc) = self.sc {
+            while sc.wants_write() && !ext.wr.is_eof() {
+                // Not expecting any error when writing to a PipeBuf
+                sc.write_tls(&mut Tee {
+                    inner: &mut ext.wr,
+                    extras: self.extras.as_deref_mut(),
+                })
+                .map_err(|e| {
+                    TlsError::Internal(format!(
+                        "Unexpected error from ServerConnection::write_tls: {e}"
+                    ))
+                })?;
+            }
+        }
+        if !ext.wr.is_eof() {
+            ext.wr.push();
+        }
+        Ok(())
+    }
+
+    /// Process as for [`TlsServer::process`], but retry any recoverable
+    /// failure a bounded number of times before returning an error.
+    /// The buffered wrapper has no recoverable failures, because
+    /// Rustls buffers internally and writes to a pipe always
+    /// succeed, and errors from Rustls itself (e.g. a failure to
+    /// decrypt) are always fatal.  So this is the same as
+    /// [`TlsServer::process`], and is provided so that code can use the
+    /// same call for both the buffered and unbuffered wrappers.
+    pub fn process_retry(&mut self, ext: PBufRdWr, int: PBufRdWr) -> Result<bool, TlsError> {
+        self.process(ext, int)
+    }
+
+    // Check that the wrapper's state and the pipe states agree after
+    // a successful `process`.  This compiles out in release builds.
+    fn debug_check(&self, ext: &PBufRdWr, int: &PBufRdWr) {
+        debug_assert!(
+            self.outbound_termination.is_none() || ext.wr.is_eof(),
+            "ext.wr termination recorded but ext.wr is still open"
+        );
+        debug_assert!(
+            !self.read_closed || int.wr.is_eof(),
+            "Read side marked closed but int.wr is still open"
+        );
+        debug_assert!(
+            !self.write_closed || int.rd.is_done() || ext.wr.is_eof(),
+            "Write side marked closed but neither int.rd nor ext.wr has ended"
+        );
+        debug_assert!(
+            self.sc.is_none()
+                || self.outbound_termination != Some(Termination::Close)
+                || self.close_notify_sent,
+            "ext.wr closed cleanly without sending close_notify"
+        );
+    }
+
+    /// Process incoming TLS data from `ext` and return a view of the
+    /// next chunk of decrypted plain-text directly from the Rustls
+    /// buffer, without copying it into a pipe.  This is for
+    /// zero-copy parsers.  Any handshake or other TLS data due to be
+    /// sent is written to `ext.wr`.  The returned chunk counts as
+    /// consumed on the next call to this method,
+    /// [`TlsServer::process`] or [`TlsServer::drain_remaining`], so it must be
+    /// used or copied before then.  The same checks and limits
+    /// apply as for `process`, and any failure is kept for
+    /// `recent_errors`.
+    ///
+    /// Returns `Ok(None)` if no plain-text is available yet, which
+    /// includes whilst waiting for the ClientHello or whilst paused,
+    /// and `Ok(Some(&[]))` once the peer has sent `close_notify` and
+    /// all the data has been read.  End-of-file on `ext.rd` is not
+    /// handled here, so call [`TlsServer::process`] to pass that on.
+    /// Fails if TLS is not active.
+    pub fn process_read_borrowed(&mut self, mut ext: PBufRdWr) -> Result<Option<&[u8]>, TlsError> {
+        self.release_borrowed();
+        match self.read_borrowed_inner(ext.reborrow()) {
+            Ok(activity) => self.started |= activity,
+            Err(e) => {
+                crate::record_error(&mut self.recent_errors, &e);
+                return Err(e);
+            }
+        }
+        let sc = match self.sc {
+            // Plain-text is left in Rustls whilst paused
+            Some(ref mut sc) if !self.paused => sc,
+            _ => return Ok(None),
+        };
+        match sc.reader().into_first_chunk() {
+            Ok(chunk) => {
+                self.borrowed_len = chunk.len();
+                self.bytes_since_refresh += chunk.len() as u64;
+                self.plaintext_waiting = self.plaintext_waiting.saturating_sub(chunk.len());
+                Ok(Some(chunk))
+            }
+            Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(None),
+            Err(e) => {
+                let e = TlsError::Decode(format!("TLS read error: {e}"));
+                crate::record_error(&mut self.recent_errors, &e);
+                Err(e)
+            }
+        }
+    }
+
+    // Body of `process_read_borrowed`, without the recording of
+    // errors: pass any ClientHello to the selector, write out
+    // whatever is queued, and read from `ext.rd` until Rustls has
+    // plain-text to return or can do nothing more
+    fn read_borrowed_inner(&mut self, mut ext: PBufRdWr) -> Result<bool, TlsError> {
+        if self.sc.is_none() && self.accept.is_none() {
+            return Err(TlsError::Usage("TLS is not active".into()));
+        }
+        let before = tripwire!(ext.rd, ext.wr);
+        self.accept_client_hello(ext.reborrow())?;
+        if self.sc.is_some() {
+            for _ in 0..self.max_iterations {
+                self.check_handshake(ext.reborrow())?;
+                if self.write_ext(ext.reborrow())? {
+                    continue;
+                }
+                if self.paused || self.read_ext(ext.reborrow())?.is_none() {
+                    break;
+                }
+            }
+        }
+        let after = tripwire!(ext.rd, ext.wr);
+        Ok(after != before)
+    }
+
+    // Whilst waiting for the ClientHello, pass `ext.rd` to the
+    // acceptor, and create the connection once the selector has
+    // chosen its configuration.  Nothing is read once the transport
+    // has failed.
+    fn accept_client_hello(&mut self, mut ext: PBufRdWr) -> Result<(), TlsError> {
+        if self.transport_failed {
+            return Ok(());
+        }
+        let Some(ref mut accept) = self.accept else {
+            return Ok(());
+        };
+        // Keep a copy of what the acceptor reads if it needs to be
+        // captured, watched or checked for record lengths
+        let waiting = self.extras.is_some().then(|| ext.rd.data().to_vec());
+        if let Some(ref data) = waiting {
+            if let Some(mut check) = self.extras.as_ref().and_then(|x| x.record_limit) {
+                check.scan(data)?;
+            }
+        }
+        let result = accept.process(&mut ext, self.alert_on_error, &mut self.recent_errors);
+        if let (Some(data), Some(extras)) = (waiting, self.extras.as_mut()) {
+            let data = &data[..data.len() - ext.rd.data().len()];
+            if let Some(ref mut limit) = extras.record_limit {
+                limit.scan(data)?;
+            }
+            extras.observe(false, data);
+        }
+        if result.is_err() && !self.alert_on_error && !ext.wr.is_eof() {
+            // Close without revealing the reason
+            ext.wr.abort();
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        }
+        if let Some((sc, half_rtt)) = result? {
+            self.sc = Some(sc);
+            self.half_rtt = half_rtt;
+            self.accept = None;
+        }
+        Ok(())
+    }
+
+    // Check for SNI once the ClientHello has been handled, if it is
+    // required, before anything is sent back
+    fn check_handshake(&mut self, mut ext: PBufRdWr) -> Result<(), TlsError> {
+        let Some(ref sc) = self.sc else {
+            return Ok(());
+        };
+        if self.require_sni && sc.protocol_version().is_some() && sc.server_name().is_none() {
+            // Nothing has been sent yet, so the ServerHello that
+            // Rustls has queued can be replaced with an unencrypted
+            // `unrecognized_name` alert.  Rustls can't be asked to
+            // send an alert, so it is written here, and `ext.wr` is
+            // closed so that the ServerHello never follows it.
+            if !ext.wr.is_eof() {
+                if self.alert_on_error {
+                    let alert = record::plaintext_alert(AlertDescription::UnrecognisedName);
+                    let mut tee = Tee {
+                        inner: &mut ext.wr,
+                        extras: self.extras.as_deref_mut(),
+                    };
+                    if let Err(e) = tee.write_all(&alert) {
+                        crate::record_error(
+                            &mut self.recent_errors,
+                            &TlsError::Internal(format!("Failed to send alert: {e}")),
+                        );
+                    }
+                    ext.wr.close();
+                    self.outbound_termination.get_or_insert(Termination::Close);
+                } else {
+                    // Close without revealing the reason
+                    ext.wr.abort();
+                    self.outbound_termination.get_or_insert(Termination::Abort);
+                }
+            }
+            return Err(TlsError::Check("Client did not send SNI".into()));
+        }
+        Ok(())
+    }
+
+    // ServerConnection -> ext.wr.  Returns `true` if anything was
+    // written.
+    fn write_ext(&mut self, mut ext: PBufRdWr) -> Result<bool, TlsError> {
+        let Some(ref mut sc) = self.sc else {
+            return Ok(false);
+        };
+        if !sc.wants_write() || ext.wr.is_eof() {
+            return Ok(false);
+        }
+        // We're not expecting any error from this as PipeBuf Write
+        // implementation doesn't return Err and `write_tls` is just
+        // copying from an internal Rustls buffer.
+        sc.write_tls(&mut Tee {
+            inner: &mut ext.wr,
+            extras: self.extras.as_deref_mut(),
+        })
+        .map_err(|e| {
+            TlsError::Internal(format!(
+                "Unexpected error from ServerConnection::write_tls: {e}"
+            ))
+        })?;
+        self.flight_sent |= sc.is_handshaking();
+        if self.single_record {
+            ext.wr.push();
+        }
+        // If we've done a `send_close_notify` and Rustls has nothing
+        // more to write, it's time to close the TLS outgoing stream too
+        if self.close_notify_sent && !sc.wants_write() && self.close_transport {
+            ext.wr.close();
+            self.outbound_termination.get_or_insert(Termination::Close);
+        }
+        Ok(true)
+    }
+
+    // ext.rd -> ServerConnection, taking any bytes passed at creation
+    // first, then process the new packets.  Returns the number of
+    // bytes of plain-text waiting in Rustls, or `None` if nothing was
+    // read.  If Rustls fails, its alert is flushed to `ext.wr` first,
+    // or `ext.wr` is aborted if alerts are disabled.
+    fn read_ext(&mut self, mut ext: PBufRdWr) -> Result<Option<usize>, TlsError> {
+        let Some(ref mut sc) = self.sc else {
+            return Ok(None);
+        };
+        // Rustls stops asking for more whilst it holds plain-text,
+        // but whilst paused, records are still read so that alerts,
+        // `close_notify` and key updates are handled, until Rustls
+        // holds as much as it will take
+        let wants_read = sc.wants_read()
+            || (self.paused
+                && !self.close_notify_received
+                && self.plaintext_waiting <= record::RECEIVED_PLAINTEXT_LIMIT);
+        if !wants_read || (ext.rd.is_empty() && self.prebuffered.is_empty()) {
+            return Ok(None);
+        }
+        // Data arriving after a handshake flight was sent is the
+        // reply, completing a round trip
+        if self.flight_sent && sc.is_handshaking() {
+            self.round_trips = self.round_trips.saturating_add(1);
+            self.flight_sent = false;
+        }
+        // We don't expect any error from this.  Reading from a slice
+        // doesn't return an error.  The call may return an error if
+        // its buffer is full, but we only call it when it wants more
+        // data.
+        let from_prebuffered = !self.prebuffered.is_empty();
+        let data = if from_prebuffered {
+            // Bytes passed at creation come before ext.rd
+            &self.prebuffered[..]
+        } else if self.single_record {
+            let data = ext.rd.data();
+            &data[..record::first_record_len(data)]
+        } else {
+            ext.rd.data()
+        };
+        if let Some(mut check) = self.extras.as_ref().and_then(|x| x.record_limit) {
+            check.scan(data)?;
+        }
+        let count = sc.read_tls(&mut &data[..]).map_err(|e| {
+            TlsError::Internal(format!(
+                "Unexpected failure from ServerConnection::read_tls: {e}"
+            ))
+        })?;
+        if let Some(ref mut extras) = self.extras {
+            if let Some(ref mut limit) = extras.record_limit {
+                limit.scan(&data[..count])?;
+            }
+            extras.observe(false, &data[..count]);
+        }
+        if from_prebuffered {
+            self.prebuffered.drain(..count);
+        } else {
+            ext.rd.consume(count);
+        }
+
+        let state = match sc.process_new_packets() {
+            Ok(state) => state,
+            Err(e) => {
+                if !ext.wr.is_eof() {
+                    if self.alert_on_error {
+                        // Flush the fatal alert that Rustls has queued,
+                        // so that the peer learns the reason.  Any
+                        // failure is kept for `recent_errors`.
+                        if let Err(e) = sc.write_tls(&mut Tee {
+                            inner: &mut ext.wr,
+                            extras: self.extras.as_deref_mut(),
+                        }) {
+                            crate::record_error(
+                                &mut self.recent_errors,
+                                &TlsError::Internal(format!("Failed to send alert: {e}")),
+                            );
+                        }
+                    } else {
+                        // Close without revealing the reason
+                        ext.wr.abort();
+                        self.outbound_termination.get_or_insert(Termination::Abort);
+                    }
+                }
+                return Err(TlsError::from(e));
+            }
+        };
+        self.close_notify_received |= state.peer_has_closed();
+        self.plaintext_waiting = state.plaintext_bytes_to_read();
+        Ok(Some(self.plaintext_waiting))
+    }
+
+    // ServerConnection -> int.wr: first any TLS 1.3 0-RTT early data,
+    // then `available` bytes of plain-text just decrypted, passing on
+    // at most `limit` bytes in all.  Whilst paused, or beyond the
+    // limit, the rest is held in Rustls.
+    fn pass_plaintext(
+        &mut self,
+        available: usize,
+        mut int: PBufWr,
+        limit: usize,
+    ) -> Result<(), TlsError> {
+        let Some(ref mut sc) = self.sc else {
+            return Ok(());
+        };
+        if self.paused {
+            // Leave it in Rustls until resumed.  How much early data
+            // is waiting isn't known, so assume there may be some.
+            if available > 0 || sc.early_data().is_some() {
+                self.plaintext_held = true;
+            }
+        } else if !int.is_eof() {
+            let early = pass_early_data(sc, &mut int, limit)?;
+            self.bytes_since_refresh += early as u64;
+            let limit = limit - early;
+            let read_len = available.min(limit);
+            if read_len < available || limit == 0 {
+                // Leave the rest in Rustls until `int.wr` has room
+                self.plaintext_held = true;
+            }
+            if read_len > 0 {
+                self.bytes_since_refresh += read_len as u64;
+                self.plaintext_waiting = self.plaintext_waiting.saturating_sub(read_len);
+                if let Err(e) = int.input_from(&mut sc.reader(), read_len) {
+                    match e.kind() {
+                        ErrorKind::WouldBlock => self.would_block_count += 1,
+                        ErrorKind::UnexpectedEof => int.abort(),
+                        _ => return Err(TlsError::Decode(format!("TLS read error: {e}"))),
+                    }
+                }
+            }
+            if self.single_record && early + read_len > 0 {
+                int.push();
+            }
+        }
+        Ok(())
+    }
+
+    /// Get the rarely used state, allocating it on first use
+    fn extras(&mut self) -> &mut Extras {
+        self.extras.get_or_insert_with(Default::default)
+    }
+
+    // Consume the chunk last returned by `process_read_borrowed`
+    fn release_borrowed(&mut self) {
+        if self.borrowed_len > 0 {
+            if let Some(ref mut sc) = self.sc {
+                sc.reader().consume(self.borrowed_len);
+            }
+            self.borrowed_len = 0;
+        }
+    }
+
+    // Body of `process`, without the recording of errors
+    fn process_inner(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) -> Result<bool, TlsError> {
+        let before = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
+        let mut capped = false;
+
+        // Plain-text is passed to `int.wr` whilst it holds no more
+        // than `watermark` bytes, and at most `limit` bytes at a time.
+        // Single-record mode waits for each record to be consumed.
+        let (watermark, limit) = if self.single_record {
+            let limit = self
+                .max_plaintext_buffered
+                .min(record::MAX_RECORD_PLAINTEXT);
+            (0, limit)
+        } else {
+            (self.max_plaintext_buffered, self.max_plaintext_buffered)
+        };
+
+        // Pass on any plain-text held in Rustls whilst paused or
+        // whilst `int.wr` was full
+        if self.plaintext_held
+            && !self.paused
+            && !int.wr.is_eof()
+            && !int.wr.exceeds_limit(watermark)
+        {
+            self.drain_plaintext(int.wr.reborrow(), watermark, limit)?;
+            // Anything more waits for `int.wr` to be consumed
+            self.plaintext_held = int.wr.exceeds_limit(watermark);
+        }
+
+        self.accept_client_hello(ext.reborrow())?;
+
+        if self.transport_failed {
+            crate::abort_all(&mut ext, &mut int);
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        } else if self.accept.is_some() {
+            // Still waiting for the ClientHello
+            if ext.rd.has_pending_eof() {
+                crate::abort_all(&mut ext, &mut int);
+                self.outbound_termination.get_or_insert(Termination::Abort);
+            }
+        } else if self.sc.is_some() {
+            let mut held = false;
+            let mut iterations = 0;
             loop {
+                // Hand control back to the caller if this is taking
+                // too long, reporting activity so that it calls again
+                iterations += 1;
+                if iterations > self.max_iterations {
+                    capped = true;
+                    break;
+                }
+
+                // Once the ClientHello has been handled, check for SNI
+                // before anything is sent back
+                self.check_handshake(ext.reborrow())?;
+
                 // ServerConnection -> ext.wr
-                if sc.wants_write() && !ext.wr.is_eof() {
-                    // We're not expecting any error from this as
-                    // PipeBuf Write implementation doesn't return Err
-                    // and `write_tls` is just copying from an
-                    // internal Rustls buffer.
-                    sc.write_tls(&mut ext.wr).map_err(|e| {
-                        TlsError(format!(
-                            "Unexpected error from ServerConnection::write_tls: {e}"
-                        ))
-                    })?;
-                    // If we've done a `send_close_notify` and Rustls
-                    // has nothing more to write, it's time to close
-                    // the TLS outgoing stream too
-                    if int.rd.is_done() && !sc.wants_write() {
-                        ext.wr.close();
-                    }
+                if self.write_ext(ext.reborrow())? {
                     continue;
                 }
 
-                if !sc.is_handshaking() {
+                let Some(ref mut sc) = self.sc else {
+                    break;
+                };
+                if !sc.is_handshaking() && self.draining {
+                    // Close once all accepted plain-text has been
+                    // encrypted, ignoring anything new in int.rd
+                    if !self.close_notify_sent {
+                        sc.send_close_notify();
+                        self.close_notify_sent = true;
+                        continue;
+                    }
+                } else if !self.draining && !self.paused && can_send(sc, self.half_rtt) {
                     // int.rd -> ServerConnection; flushes only on "push"
-                    if !int.rd.is_empty() {
+                    let hold = self.coalesce_held < self.coalesce_max
+                        && int.rd.data().len() < record::MAX_RECORD_PLAINTEXT
+                        && !int.rd.has_pending_eof();
+                    if !int.rd.is_empty() && hold {
+                        // Leave small writes to collect for a later call
+                        held = true;
+                    } else if !int.rd.is_empty() {
+                        let waiting = int.rd.data().len();
                         // Not expecting any error
-                        int.rd.output_to(&mut sc.writer(), false).map_err(|e| {
-                            TlsError(format!(
+                        let map_err = |e: std::io::Error| {
+                            TlsError::Internal(format!(
                                 "Unexpected error from ServerConnection::writer.write: {e}"
                             ))
-                        })?;
-                        continue;
+                        };
+                        if self.single_record || self.quota_remaining.is_some() {
+                            let data = int.rd.data();
+                            let mut len = data.len();
+                            if self.single_record {
+                                len = len.min(record::MAX_RECORD_PLAINTEXT);
+                            }
+                            if let Some(remaining) = self.quota_remaining {
+                                len = len.min(usize::try_from(remaining).unwrap_or(usize::MAX));
+                            }
+                            let count = sc.writer().write(&data[..len]).map_err(map_err)?;
+                            int.rd.consume(count);
+                            if let Some(ref mut remaining) = self.quota_remaining {
+                                *remaining -= count as u64;
+                                if *remaining == 0 {
+                                    // Quota used up, so close cleanly
+                                    self.draining = true;
+                                }
+                            }
+                        } else {
+                            int.rd.output_to(&mut sc.writer(), false).map_err(map_err)?;
+                        }
+                        self.coalesce_held = 0;
+                        let sent = (waiting - int.rd.data().len()) as u64;
+                        self.bytes_since_refresh += sent;
+                        if sc.is_handshaking() {
+                            // Sent before the client's Finished arrived
+                            self.half_rtt_bytes += sent;
+                        }
+                        if int.rd.data().len() < waiting {
+                            continue;
+                        }
+                        // Rustls accepted nothing because its buffer
+                        // limit has been reached, so don't spin
                     }
-                    // int.rd is empty
-                    if int.rd.consume_eof() {
-                        if int.rd.is_aborted() {
-                            // For Abort, don't terminate the TLS protocol
-                            // nicely.  This will result in an
-                            // UnexpectedEof at the other end.  It should
-                            // be possible (on the other end of int.rd) to
-                            // write data, push, and abort and that data
-                            // will be sent before the abort of the
-                            // ext.wr.
-                            ext.wr.abort();
+                    // int.rd is empty, unless held or stalled.  Any
+                    // close waits until the handshake is complete.
+                    if int.rd.is_empty() && !sc.is_handshaking() && int.rd.consume_eof() {
+                        let action = if int.rd.is_aborted() {
+                            self.abort_action
                         } else {
-                            // Close cleanly with a "close_notify"
-                            sc.send_close_notify();
+                            self.clean_close_action
+                        };
+                        match action {
+                            CloseAction::Abort => {
+                                // For Abort, don't terminate the TLS
+                                // protocol nicely.  This will result in
+                                // an UnexpectedEof at the other end.  It
+                                // should be possible (on the other end
+                                // of int.rd) to write data, push, and
+                                // abort and that data will be sent
+                                // before the abort of the ext.wr.
+                                ext.wr.abort();
+                                self.outbound_termination.get_or_insert(Termination::Abort);
+                            }
+                            CloseAction::CloseNotify => {
+                                // Close cleanly with a "close_notify"
+                                sc.send_close_notify();
+                                self.close_notify_sent = true;
+                            }
+                            CloseAction::KeepOpen => (),
                         }
                         continue;
                     }
                 }
 
-                // ext.rd -> ServerConnection
-                if sc.wants_read() && !ext.rd.is_empty() {
-                    // We don't expect any error from this.  The
-                    // PipeBuf Read implementation doesn't return an
-                    // error if there are bytes.  The call may return
-                    // an error if its buffer is full, but we only
-                    // call it when it wants more data.
-                    sc.read_tls(&mut ext.rd).map_err(|e| {
-                        TlsError(format!(
-                            "Unexpected failure from ServerConnection::read_tls: {e}"
-                        ))
-                    })?;
-
-                    let state = sc
-                        .process_new_packets()
-                        .map_err(|e| TlsError(format!("TLS stream error: {e}")))?;
-
-                    // ServerConnection -> int.wr
-                    if !int.wr.is_eof() {
-                        let read_len = state.plaintext_bytes_to_read();
-                        if read_len > 0 {
-                            if let Err(e) = int.wr.input_from(&mut sc.reader(), read_len) {
-                                match e.kind() {
-                                    ErrorKind::WouldBlock => (),
-                                    ErrorKind::UnexpectedEof => int.wr.abort(),
-                                    _ => return Err(TlsError(format!("TLS read error: {e}"))),
-                                }
-                            }
-                        }
+                // ext.rd -> ServerConnection, unless the internal side
+                // has closed int.wr to refuse further data, or
+                // plain-text is being held until `int.wr` has room.
+                // Whilst paused, nothing goes to `int.wr`, so reading
+                // continues.
+                let refused = (int.wr.is_eof() && !sc.is_handshaking())
+                    || (!self.paused && (self.plaintext_held || int.wr.exceeds_limit(watermark)));
+                if !refused {
+                    if let Some(available) = self.read_ext(ext.reborrow())? {
+                        // ServerConnection -> int.wr
+                        self.pass_plaintext(available, int.wr.reborrow(), limit)?;
+                        continue;
                     }
-                    continue;
                 }
 
                 // Pass through EOF from external side.  For the case
@@ -155,7 +1637,12 @@ impl TlsServer {
                 // external side, so in that case just pass the EOF
                 // through even thought there is pending data.
                 if ext.rd.has_pending_eof()
-                    && (ext.rd.is_aborted() || ext.rd.is_empty() || int.rd.is_done())
+                    && !self.paused
+                    && !self.plaintext_held
+                    && (ext.rd.is_aborted()
+                        || ext.rd.is_empty()
+                        || int.rd.is_done()
+                        || self.close_notify_sent)
                 {
                     ext.rd.consume_eof();
                     if !int.wr.is_eof() {
@@ -171,13 +1658,163 @@ impl TlsServer {
                 // Nothing left to do
                 break;
             }
-        } else {
+            if held {
+                // Report activity, so that a caller looping on
+                // `process` counts down the delay and sends the data
+                self.coalesce_held += 1;
+                capped = true;
+            }
+        } else if !self.paused {
             // TLS disabled: Pass data through unchanged
-            int.rd.forward(ext.wr.reborrow());
+            if !self.prebuffered.is_empty() {
+                int.wr.append(&self.prebuffered);
+                self.prebuffered = Vec::new();
+            }
+            if !self.draining {
+                int.rd.forward(ext.wr.reborrow());
+                if int.rd.is_done() {
+                    let how = if int.rd.is_aborted() {
+                        Termination::Abort
+                    } else {
+                        Termination::Close
+                    };
+                    self.outbound_termination.get_or_insert(how);
+                }
+            } else if !ext.wr.is_eof() {
+                ext.wr.close();
+                self.outbound_termination.get_or_insert(Termination::Close);
+            }
             ext.rd.forward(int.wr.reborrow());
         }
 
+        self.read_closed |= int.wr.is_eof();
+        self.write_closed |= int.rd.is_done() || ext.wr.is_eof();
+
         let after = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
-        Ok(after != before)
+        Ok(capped || after != before)
     }
+
+    /// Process as for [`TlsServer::process`], but return a [`Progress`]
+    /// report giving more detail about what happened during the
+    /// call.  For example, this allows a "connection ready" event to
+    /// be fired on exactly the call where the handshake completed.
+    pub fn process_report(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<Progress, TlsError> {
+        // Not complete whilst still waiting for the ClientHello
+        let was_complete = self.accept.is_none() && self.handshake_complete();
+        let close_notify_sent = self.close_notify_sent;
+        let close_notify_received = self.close_notify_received;
+        let int_done = int.rd.is_done();
+        let ext_done = ext.rd.is_done();
+        let activity = self.process(ext.reborrow(), int.reborrow())?;
+        Ok(Progress {
+            activity,
+            just_completed_handshake: !was_complete
+                && self.accept.is_none()
+                && self.handshake_complete(),
+            sent_close_notify: !close_notify_sent && self.close_notify_sent,
+            received_close_notify: !close_notify_received && self.close_notify_received,
+            sent_abort: !int_done
+                && int.rd.is_done()
+                && int.rd.is_aborted()
+                && (self.sc.is_none() || self.abort_action == CloseAction::Abort),
+            received_abort: !ext_done && ext.rd.is_done() && ext.rd.is_aborted(),
+        })
+    }
+}
+
+/// Waits for the ClientHello, and then selects the configuration to
+/// use for the connection
+struct Accept {
+    acceptor: Acceptor,
+    select: Box<dyn FnMut(&ClientHello<'_>) -> Arc<ServerConfig> + Send>,
+}
+
+impl Accept {
+    /// Pass data from `ext.rd` to the acceptor, returning the
+    /// connection once the ClientHello is complete, along with the
+    /// `send_half_rtt_data` setting of the chosen configuration.  On
+    /// failure, the alert is only sent if `send_alert` is set.  A
+    /// failure to send it is added to `errors`.
+    fn process(
+        &mut self,
+        ext: &mut PBufRdWr,
+        send_alert: bool,
+        errors: &mut Vec<TlsError>,
+    ) -> Result<Option<(ServerConnection, bool)>, TlsError> {
+        while !ext.rd.is_empty() {
+            // Not expecting any error when reading from a PipeBuf
+            self.acceptor.read_tls(&mut ext.rd).map_err(|e| {
+                TlsError::Internal(format!("Unexpected failure from Acceptor::read_tls: {e}"))
+            })?;
+            match self.acceptor.accept() {
+                Ok(None) => (),
+                Ok(Some(accepted)) => {
+                    let config = (self.select)(&accepted.client_hello());
+                    let half_rtt = config.send_half_rtt_data;
+                    return match accepted.into_connection(config) {
+                        Ok(sc) => Ok(Some((sc, half_rtt))),
+                        Err((e, alert)) => Err(Self::fail(ext, e, alert, send_alert, errors)),
+                    };
+                }
+                Err((e, alert)) => return Err(Self::fail(ext, e, alert, send_alert, errors)),
+            }
+        }
+        Ok(None)
+    }
+
+    /// Send the alert that Rustls generated to the peer if
+    /// `send_alert` is set, and convert the error
+    fn fail(
+        ext: &mut PBufRdWr,
+        err: rustls::Error,
+        mut alert: AcceptedAlert,
+        send_alert: bool,
+        errors: &mut Vec<TlsError>,
+    ) -> TlsError {
+        if send_alert && !ext.wr.is_eof() {
+            if let Err(e) = alert.write_all(&mut ext.wr) {
+                crate::record_error(
+                    errors,
+                    &TlsError::Internal(format!("Failed to send alert: {e}")),
+                );
+            }
+        }
+        TlsError::from(err)
+    }
+}
+
+/// Pass TLS 1.3 0-RTT early data to `int`, at most `limit` bytes,
+/// returning the number of bytes passed on.  Rustls only offers early
+/// data if `max_early_data_size` is set in the config.
+fn pass_early_data(
+    sc: &mut ServerConnection,
+    int: &mut PBufWr,
+    limit: usize,
+) -> Result<usize, TlsError> {
+    let Some(mut early) = sc.early_data() else {
+        return Ok(0);
+    };
+    let mut total = 0;
+    while total < limit {
+        let count = early
+            .read(int.space((limit - total).min(4096)))
+            .map_err(|e| TlsError::Decode(format!("TLS early data read error: {e}")))?;
+        int.commit(count);
+        if count == 0 {
+            break;
+        }
+        total += count;
+    }
+    Ok(total)
+}
+
+/// Check whether application data can be sent: either the handshake
+/// is complete, or this is a TLS 1.3 server configured with
+/// `send_half_rtt_data` which can send after its first flight
+fn can_send(sc: &ServerConnection, half_rtt: bool) -> bool {
+    !sc.is_handshaking() || (half_rtt && sc.protocol_version() == Some(ProtocolVersion::TLSv1_3))
 }
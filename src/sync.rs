@@ -0,0 +1,58 @@
+//! Blocking helpers for simple uses over a `Read + Write` transport
+
+use crate::TlsError;
+use pipebuf::{PBufRdWr, PipeBufPair};
+use std::io::{Read, Write};
+
+/// Send `request` through the wrapper driven by `process`, closing
+/// the outgoing stream after it, then collect everything received
+/// until the incoming stream ends.  `transport` is used in blocking
+/// mode.
+pub(crate) fn exchange(
+    mut process: impl FnMut(PBufRdWr, PBufRdWr) -> Result<bool, TlsError>,
+    transport: &mut (impl Read + Write),
+    request: &[u8],
+) -> Result<Vec<u8>, TlsError> {
+    let io_err = |e: std::io::Error| TlsError::Transport(format!("Transport error: {e}"));
+
+    // `ext` left is the wrapper and right the transport; `int` left
+    // is the protocol side and right the wrapper
+    let mut ext = PipeBufPair::new();
+    let mut int = PipeBufPair::new();
+    let mut proto = int.left();
+    proto.wr.append(request);
+    proto.wr.close();
+
+    loop {
+        process(ext.left(), int.right())?;
+
+        let proto = int.left();
+        if proto.rd.has_pending_eof() {
+            if proto.rd.is_aborted() {
+                return Err(TlsError::Transport(
+                    "Connection aborted before the response ended".into(),
+                ));
+            }
+            return Ok(proto.rd.data().to_vec());
+        }
+
+        let mut trans = ext.right();
+        let len = trans.rd.data().len();
+        if len > 0 {
+            transport.write_all(trans.rd.data()).map_err(io_err)?;
+            transport.flush().map_err(io_err)?;
+            trans.rd.consume(len);
+            continue;
+        }
+        if trans.wr.is_eof() {
+            return Err(TlsError::Transport(
+                "Transport closed before the response ended".into(),
+            ));
+        }
+        let count = transport.read(trans.wr.space(16 * 1024)).map_err(io_err)?;
+        trans.wr.commit(count);
+        if count == 0 {
+            trans.wr.close();
+        }
+    }
+}
@@ -1,10 +1,17 @@
-use crate::TlsError;
-use pipebuf::{tripwire, PBufRdWr, PBufState};
+use crate::{
+    Clock, CloseReason, ConnectionDescriptor, CryptoSummary, Progress, Termination, TlsError,
+};
+use pipebuf::{tripwire, PBufRdWr, PBufState, PBufWr};
+use rustls::client::danger::ServerCertVerifier;
 use rustls::client::UnbufferedClientConnection;
-use rustls::pki_types::ServerName;
+use rustls::client::{ClientSessionStore, Resumption};
+use rustls::pki_types::{CertificateDer, PrivateKeyDer, ServerName, UnixTime};
 use rustls::server::UnbufferedServerConnection;
-use rustls::unbuffered::ConnectionState;
-use rustls::{ClientConfig, ServerConfig};
+use rustls::unbuffered::{ConnectionState, EncodeError, EncryptError, InsufficientSizeError};
+use rustls::{
+    ClientConfig, ProtocolVersion, RootCertStore, ServerConfig, SupportedCipherSuite,
+    SupportedProtocolVersion,
+};
 use std::sync::Arc;
 
 /// Rustls-unbuffered bug/limitation: After `Closed`, no more
@@ -14,38 +21,48 @@ use std::sync::Arc;
 /// but we can fake the EOF handling.
 const FIXUP_CLOSE: bool = true;
 
+/// Default maximum space to allow for encoding a handshake record
+const DEFAULT_MAX_ENCODE_SIZE: usize = 256 * 1024;
+
+/// Default space first tried for encoding a handshake record
+const DEFAULT_ENCODE_BUFFER_SIZE: usize = 18 * 1024;
+
+/// Smallest allowed encode buffer size: enough for the largest TLS
+/// 1.3 record, i.e. header, 16KB of plain-text and the maximum
+/// expansion
+const MIN_ENCODE_BUFFER_SIZE: usize = 5 + 16384 + 256;
+
+/// Number of times `process_retry` doubles the encoding space limit
+/// for a handshake record that doesn't fit
+const MAX_ENCODE_RETRIES: u32 = 3;
+
 macro_rules! read_early_data {
     (true, $red:ident, $discard:ident, $int:ident) => {{
         // Accept early data, despite security concerns.  The caller
         // can limit early data in the config.
         while let Some(rec) = $red.next_record() {
-            let rec =
-                rec.map_err(|e| TlsError(format!("Failed fetching TLS incoming data: {e}")))?;
+            let rec = rec.map_err(|e| TlsError::rustls("Failed fetching TLS incoming data", e))?;
             $discard += rec.discard;
             $int.wr.append(rec.payload);
         }
     }};
     (false, $red:ident, $discard:ident, $int:ident) => {{
-        return Err(TlsError("Not expecting early data on client".into()));
+        return Err(TlsError::Decode(
+            "Not expecting early data on client".into(),
+        ));
     }};
 }
 
 // To share processing code requires a macro, due to static typing of
 // the unbuffered API (no traits)
 macro_rules! process {
-    ($ext:ident, $int:ident, $conn:ident, $is_server:tt) => {{
+    ($ext:ident, $int:ident, $conn:ident, $is_server:tt,
+     $encode_size:expr, $max_encode:expr, $retries:expr, $close_transport:expr, $sent:expr, $received:expr,
+     $outbound:expr, $on_record:expr) => {{
         if $int.rd.is_aborted() || $ext.rd.is_aborted() {
             // Give up totally on abort in either direction
-            $int.rd.consume($int.rd.data().len());
-            $int.rd.consume_eof();
-            $ext.rd.consume($ext.rd.data().len());
-            $ext.rd.consume_eof();
-            if !$ext.wr.is_eof() {
-                $ext.wr.abort();
-            }
-            if !$int.wr.is_eof() {
-                $int.wr.abort();
-            }
+            crate::abort_all(&mut $ext, &mut $int);
+            $outbound.get_or_insert(Termination::Abort);
         } else {
             let mut discard = 0;
             loop {
@@ -63,34 +80,40 @@ macro_rules! process {
                         $int.rd.consume($int.rd.data().len());
                         if $int.rd.is_aborted() {
                             $ext.wr.abort();
-                        } else {
+                            $outbound.get_or_insert(Termination::Abort);
+                        } else if $close_transport {
                             $ext.wr.close();
+                            $outbound.get_or_insert(Termination::Close);
                         }
                     }
                     break;
                 }
 
+                let version = $conn.protocol_version().unwrap_or(ProtocolVersion::TLSv1_3);
                 let status = $conn.process_tls_records($ext.rd.data_mut());
                 discard += status.discard;
                 let state = status.state.map_err(|e| {
-                    TlsError(format!(
-                        "Failed whilst processing incoming TLS records: {e}"
-                    ))
+                    TlsError::rustls("Failed whilst processing incoming TLS records", e)
                 })?;
                 match state {
                     ConnectionState::ReadTraffic(mut rt) => {
                         while let Some(rec) = rt.next_record() {
                             let rec = rec.map_err(|e| {
-                                TlsError(format!("Failed fetching TLS incoming data: {e}"))
+                                TlsError::rustls("Failed fetching TLS incoming data", e)
                             })?;
                             discard += rec.discard;
-                            $int.wr.append(rec.payload);
+                            if let Some(ref mut on_record) = $on_record {
+                                on_record(rec.payload);
+                            } else {
+                                $int.wr.append(rec.payload);
+                            }
                         }
                     }
                     ConnectionState::ReadEarlyData(mut _red) => {
                         read_early_data!($is_server, _red, discard, $int);
                     }
                     ConnectionState::Closed => {
+                        $received = true;
                         if !$int.wr.is_eof() {
                             $int.wr.close();
                         }
@@ -98,19 +121,42 @@ macro_rules! process {
                             $int.rd.consume($int.rd.data().len());
                             if $int.rd.is_aborted() {
                                 $ext.wr.abort();
-                            } else {
+                                $outbound.get_or_insert(Termination::Abort);
+                            } else if $close_transport {
                                 $ext.wr.close();
+                                $outbound.get_or_insert(Termination::Close);
                             }
                         }
                         break;
                     }
                     ConnectionState::EncodeTlsData(mut etd) => {
                         // The Rustls 0.23 API doesn't tell us how much
-                        // space is required.  Apparently could require up
-                        // to 18KB.
-                        let len = etd.encode($ext.wr.space(18 * 1024)).map_err(|e| {
-                            TlsError(format!("Failed to write TLS handshake record: {e}"))
-                        })?;
+                        // space is required up front.  Apparently 18KB
+                        // is usually enough, but large certificate
+                        // chains may need more, in which case retry
+                        // with the size that Rustls asks for.
+                        let mut size = $encode_size.min($max_encode);
+                        let mut limit = $max_encode;
+                        let mut retries = $retries;
+                        let len = loop {
+                            match etd.encode($ext.wr.space(size)) {
+                                Ok(len) => break len,
+                                Err(EncodeError::InsufficientSize(InsufficientSizeError {
+                                    required_size,
+                                })) if required_size <= limit => size = required_size,
+                                Err(EncodeError::InsufficientSize(_)) if retries > 0 => {
+                                    // Recoverable for `process_retry`, so
+                                    // allow more space
+                                    retries -= 1;
+                                    limit = limit.saturating_mul(2);
+                                }
+                                Err(e) => {
+                                    return Err(TlsError::Encode(format!(
+                                        "Failed to write TLS handshake record: {e}"
+                                    )))
+                                }
+                            }
+                        };
                         if !$ext.wr.is_eof() {
                             $ext.wr.commit(len);
                         }
@@ -131,13 +177,24 @@ macro_rules! process {
                             break;
                         }
                         if len > 0 && wr_open {
-                            // Rustls doesn't give us a way to tell how
-                            // much space is required for TLS overheads.
-                            // Allow the larger of 12% or 100 bytes.
-                            let space = $ext.wr.space(len + (len >> 3).max(100));
-                            let written = wt.encrypt(data, space).map_err(|e| {
-                                TlsError(format!("Error encrypting outgoing data: {e}"))
-                            })?;
+                            // Rustls doesn't tell us up front how much
+                            // space is required for TLS overheads, so
+                            // estimate it, and if that is short then
+                            // retry with the size Rustls asks for.
+                            let mut size = crate::tls_overhead(len, version);
+                            let written = loop {
+                                match wt.encrypt(data, $ext.wr.space(size)) {
+                                    Ok(written) => break written,
+                                    Err(EncryptError::InsufficientSize(
+                                        InsufficientSizeError { required_size },
+                                    )) if required_size > size => size = required_size,
+                                    Err(e) => {
+                                        return Err(TlsError::Encode(format!(
+                                            "Error encrypting outgoing data: {e}"
+                                        )))
+                                    }
+                                }
+                            };
                             $ext.wr.commit(written);
                             $int.rd.consume(len);
                         }
@@ -149,15 +206,25 @@ macro_rules! process {
                             $int.rd.consume_eof();
                             let space = $ext.wr.space(1024);
                             let written = wt.queue_close_notify(space).map_err(|e| {
-                                TlsError(format!("Error encrypting outgoing close_notify: {e}"))
+                                TlsError::Encode(format!(
+                                    "Error encrypting outgoing close_notify: {e}"
+                                ))
                             })?;
                             if wr_open {
                                 $ext.wr.commit(written);
-                                $ext.wr.close();
+                                $sent = true;
+                                if $close_transport {
+                                    $ext.wr.close();
+                                    $outbound.get_or_insert(Termination::Close);
+                                }
                             }
                         }
                     }
-                    _ => return Err(TlsError(format!("Unexpected TLS state: {state:?}"))),
+                    _ => {
+                        return Err(TlsError::Internal(format!(
+                            "Unexpected TLS state: {state:?}"
+                        )))
+                    }
                 }
             }
             $ext.rd.consume(discard);
@@ -177,6 +244,20 @@ macro_rules! process {
 /// [**Rustls**]: https://crates.io/crates/rustls
 pub struct TlsServer {
     sc: Option<UnbufferedServerConnection>,
+    transport_failed: bool,
+    encode_buffer_size: usize,
+    max_encode_size: usize,
+    encode_retries: u32,
+    close_transport: bool,
+    close_notify_sent: bool,
+    close_notify_received: bool,
+    recent_errors: Vec<TlsError>,
+    outbound_termination: Option<Termination>,
+    close_reason: Option<CloseReason>,
+    started: bool,
+    read_closed: bool,
+    write_closed: bool,
+    on_record: Option<Box<dyn FnMut(&[u8]) + Send>>,
 }
 
 impl TlsServer {
@@ -191,7 +272,81 @@ impl TlsServer {
             None
         };
 
-        Ok(Self { sc })
+        Ok(Self {
+            sc,
+            transport_failed: false,
+            encode_buffer_size: DEFAULT_ENCODE_BUFFER_SIZE,
+            max_encode_size: DEFAULT_MAX_ENCODE_SIZE,
+            encode_retries: 0,
+            close_transport: true,
+            close_notify_sent: false,
+            close_notify_received: false,
+            recent_errors: Vec::new(),
+            outbound_termination: None,
+            close_reason: None,
+            started: false,
+            read_closed: false,
+            write_closed: false,
+            on_record: None,
+        })
+    }
+
+    /// Switch a wrapper created without a configuration over to TLS,
+    /// STARTTLS-style.  Calls to [`TlsServer::process`] after this perform
+    /// the TLS handshake and then encrypt and decrypt data as normal.
+    /// Passthrough mode holds no data internally, so nothing is lost
+    /// at the switch-over, but the caller must make sure that the
+    /// plain-text protocol exchange has completed before switching,
+    /// i.e. that no more plain-text is expected from the peer.
+    ///
+    /// Fails if TLS is already active, or if Rustls rejects the
+    /// configuration.
+    pub fn activate_tls(&mut self, config: Arc<ServerConfig>) -> Result<(), rustls::Error> {
+        if self.sc.is_some() {
+            return Err(rustls::Error::General("TLS is already active".into()));
+        }
+        self.sc = Some(UnbufferedServerConnection::new(config)?);
+        Ok(())
+    }
+
+    /// Check that a configuration is accepted by Rustls, by doing a
+    /// trial construction of a connection and then discarding it.
+    /// This allows configurations to be checked at startup rather
+    /// than when the first connection is made.
+    pub fn validate_config(config: Arc<ServerConfig>) -> Result<(), rustls::Error> {
+        UnbufferedServerConnection::new(config).map(|_| ())
+    }
+
+    /// Create a new TLS engine as for [`TlsServer::new`], but with Rustls
+    /// getting the current time from `clock`, for example to make
+    /// certificate validity and ticket ages deterministic in tests.
+    /// This clones the configuration to replace its `time_provider`,
+    /// so for many connections it is cheaper to set the
+    /// `time_provider` on a shared configuration instead.
+    pub fn new_with_clock(
+        config: Option<Arc<ServerConfig>>,
+        clock: fn() -> UnixTime,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|conf| {
+            let mut conf = (*conf).clone();
+            conf.time_provider = Arc::new(Clock(clock));
+            Arc::new(conf)
+        }))
+    }
+
+    /// Create a new TLS engine as for [`TlsServer::new`], but accepting
+    /// the given ALPN protocols for this connection, in order of
+    /// preference, instead of the configuration's `alpn_protocols`
+    /// list.  This clones the configuration to make the change.
+    pub fn new_with_alpn_protocols(
+        config: Option<Arc<ServerConfig>>,
+        protocols: Vec<Vec<u8>>,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|conf| {
+            let mut conf = (*conf).clone();
+            conf.alpn_protocols = protocols;
+            Arc::new(conf)
+        }))
     }
 
     /// Get immutable access to the wrapped
@@ -200,6 +355,287 @@ impl TlsServer {
         self.sc.as_ref()
     }
 
+    /// Get mutable access to the wrapped
+    /// `UnbufferedServerConnection`, if available.  Take care, since
+    /// anything done to the connection behind the wrapper's back may
+    /// upset [`TlsServer::process`].
+    pub fn connection_mut(&mut self) -> Option<&mut UnbufferedServerConnection> {
+        self.sc.as_mut()
+    }
+
+    /// Indicate that the transport has failed, e.g. a read or write
+    /// on the underlying socket returned an `io::Error`.  Aborts
+    /// `ext.wr` immediately, and on the next call to
+    /// [`TlsServer::process`] any pending data is discarded and `int.wr`
+    /// is aborted, so that the internal handlers see an "Aborting"
+    /// end-of-file.  The wrapper passes no more data after this call.
+    pub fn transport_error(&mut self, mut ext: PBufRdWr) {
+        self.transport_failed = true;
+        self.close_reason.get_or_insert(CloseReason::TransportError);
+        if !ext.wr.is_eof() {
+            ext.wr.abort();
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        }
+    }
+
+    /// Get the IANA name of the negotiated cipher suite, for example
+    /// `"TLS13_AES_128_GCM_SHA256"`, suitable for use as a metrics
+    /// label.  Returns `None` if TLS is disabled, or if the cipher
+    /// suite has not been negotiated yet.
+    pub fn cipher_suite_name(&self) -> Option<&'static str> {
+        self.sc
+            .as_ref()
+            .and_then(|sc| sc.negotiated_cipher_suite())
+            .and_then(|suite| suite.suite().as_str())
+    }
+
+    /// Get the negotiated protocol version, e.g. for logging.
+    /// Returns `None` if TLS is disabled or the handshake has not
+    /// completed yet.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        let sc = self.sc.as_ref().filter(|sc| !sc.is_handshaking())?;
+        sc.protocol_version()
+    }
+
+    /// Get the negotiated cipher suite, e.g. for compliance checks.
+    /// Returns `None` if TLS is disabled or the handshake has not
+    /// completed yet.
+    pub fn negotiated_cipher_suite(&self) -> Option<SupportedCipherSuite> {
+        let sc = self.sc.as_ref().filter(|sc| !sc.is_handshaking())?;
+        sc.negotiated_cipher_suite()
+    }
+
+    /// Check whether the TLS handshake has completed.  Returns
+    /// `false` if TLS is disabled.  To act exactly once on
+    /// completion, see [`TlsServer::process_report`].
+    pub fn handshake_complete(&self) -> bool {
+        self.sc.as_ref().is_some_and(|sc| !sc.is_handshaking())
+    }
+
+    /// Check whether TLS 1.3 was negotiated.  Returns `false` if TLS
+    /// is disabled or the version has not been negotiated yet.
+    pub fn is_tls13(&self) -> bool {
+        self.sc.as_ref().and_then(|sc| sc.protocol_version()) == Some(ProtocolVersion::TLSv1_3)
+    }
+
+    /// Indicate that the transport has seen the peer vanish without a
+    /// clean shutdown, e.g. a connection reset.  Unlike
+    /// [`TlsServer::transport_error`], this takes effect immediately:
+    /// pending data is discarded and both `ext.wr` and `int.wr` are
+    /// aborted, so that the internal handlers see an "Aborting"
+    /// end-of-file straight away.  The wrapper passes no more data
+    /// after this call.
+    pub fn notify_peer_reset(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) {
+        self.transport_failed = true;
+        self.close_reason.get_or_insert(CloseReason::RemoteReset);
+        self.read_closed = true;
+        self.write_closed = true;
+        crate::abort_all(&mut ext, &mut int);
+        self.outbound_termination.get_or_insert(Termination::Abort);
+    }
+
+    /// Check whether the incoming plain-text stream has ended, i.e.
+    /// whether `int.wr` has been closed or aborted
+    pub fn read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Check whether the outgoing plain-text stream has ended, i.e.
+    /// whether end-of-file from `int.rd` has been handled, or `ext.wr`
+    /// has been closed or aborted.  This together with
+    /// [`TlsServer::read_closed`] makes half-closed states observable.
+    pub fn write_closed(&self) -> bool {
+        self.write_closed
+    }
+
+    /// Check whether the transport can be half-closed, e.g. with
+    /// `shutdown(Write)` on a TCP socket.  This is `true` once our
+    /// `close_notify` has been written to `ext.wr` whilst incoming
+    /// data is still expected, i.e. before the peer has closed its
+    /// side.
+    pub fn wants_transport_half_close(&self) -> bool {
+        self.close_notify_sent && !self.read_closed && !self.close_notify_received
+    }
+
+    /// Get the negotiated ALPN protocol.  Returns `None` if TLS is
+    /// disabled, if the handshake has not got far enough yet, or if
+    /// no protocol was agreed.  The result is only meaningful once
+    /// the handshake is complete, i.e. once `is_handshaking()` on the
+    /// connection returns `false`, so a `None` before then doesn't
+    /// mean that no protocol will be agreed.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.sc.as_ref().and_then(|sc| sc.alpn_protocol())
+    }
+
+    /// Get the name of the negotiated key exchange group, for example
+    /// `"X25519"` or `"X25519MLKEM768"`, suitable for use as a
+    /// metrics label.  Returns `None` if TLS is disabled, or if key
+    /// exchange has not completed yet.
+    pub fn key_exchange_group_str(&self) -> Option<&'static str> {
+        self.sc
+            .as_ref()
+            .and_then(|sc| sc.negotiated_key_exchange_group())
+            .and_then(|group| group.name().as_str())
+    }
+
+    /// Get a summary of the negotiated cryptographic parameters, e.g.
+    /// for a compliance report.  Returns `None` if TLS is disabled or
+    /// the handshake has not completed yet.
+    pub fn crypto_summary(&self) -> Option<CryptoSummary> {
+        let sc = self.sc.as_ref()?;
+        if sc.is_handshaking() {
+            return None;
+        }
+        Some(CryptoSummary {
+            version: sc.protocol_version()?,
+            cipher_suite: sc.negotiated_cipher_suite()?.suite(),
+            kx_group: sc.negotiated_key_exchange_group().map(|group| group.name()),
+        })
+    }
+
+    /// Get the most recent errors returned by [`TlsServer::process`],
+    /// oldest first, for diagnostics when one failure leads to
+    /// others.  Only the last few errors are kept.
+    pub fn recent_errors(&self) -> &[TlsError] {
+        &self.recent_errors
+    }
+
+    /// Check how this wrapper ended the outgoing stream on `ext.wr`,
+    /// e.g. to decide whether to shut down a TCP connection with a
+    /// FIN or to reset it.  Returns `None` if `ext.wr` has not been
+    /// closed or aborted by the wrapper yet.
+    pub fn outbound_termination(&self) -> Option<Termination> {
+        self.outbound_termination
+    }
+
+    /// Get the reason the connection was ended by the transport, if
+    /// [`TlsServer::transport_error`] or [`TlsServer::notify_peer_reset`] has
+    /// been called.  The first reason reported is kept.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    /// Get the certificate chain presented by the peer, end-entity
+    /// certificate first, e.g. for authorization decisions.  Returns
+    /// `None` if TLS is disabled or if the peer hasn't presented a
+    /// certificate (yet).
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.sc.as_ref()?.peer_certificates()
+    }
+
+    /// Get the end of the validity period ("notAfter") of the
+    /// peer's end-entity certificate, e.g. to monitor for
+    /// certificates that will soon expire.  Returns `None` if TLS is
+    /// disabled, if the peer hasn't presented a certificate (yet),
+    /// or if it can't be parsed.
+    pub fn peer_cert_not_after(&self) -> Option<UnixTime> {
+        let cert = self.sc.as_ref()?.peer_certificates()?.first()?;
+        crate::cert::not_after(cert)
+    }
+
+    /// Get a summary of this wrapper's mode and negotiated
+    /// parameters, for logging or serialization
+    pub fn descriptor(&self) -> ConnectionDescriptor {
+        let sc = self.sc.as_ref();
+        ConnectionDescriptor {
+            server: true,
+            tls: sc.is_some(),
+            protocol_version: sc
+                .and_then(|sc| sc.protocol_version())
+                .and_then(|version| version.as_str())
+                .map(String::from),
+            cipher_suite: self.cipher_suite_name().map(String::from),
+            alpn_protocol: sc.and_then(|sc| sc.alpn_protocol()).map(|p| p.to_vec()),
+        }
+    }
+
+    /// Set the maximum space to allow for encoding an outgoing
+    /// handshake record.  Rustls doesn't say how much space it needs
+    /// up front, so the encode buffer size (or this limit if lower)
+    /// is tried first, and if Rustls asks for more then that is
+    /// allowed up to this limit.  The default is 256KB, which is
+    /// enough for very large certificate chains.
+    pub fn set_max_encode_size(&mut self, bytes: usize) {
+        self.max_encode_size = bytes;
+    }
+
+    /// Set the space first tried for encoding an outgoing handshake
+    /// record, which defaults to 18KB.  A lower value reduces the
+    /// space reserved in `ext.wr` on each call, e.g. for embedded
+    /// use, with a retry if Rustls asks for more, as for
+    /// [`TlsServer::set_max_encode_size`].  Fails if `bytes` is less than
+    /// the size of the largest TLS 1.3 record, 16645 bytes, since
+    /// encoding could then fail for lack of space.
+    pub fn set_encode_buffer_size(&mut self, bytes: usize) -> Result<(), TlsError> {
+        if bytes < MIN_ENCODE_BUFFER_SIZE {
+            return Err(TlsError::Usage(format!(
+                "Encode buffer size must be at least {MIN_ENCODE_BUFFER_SIZE}: {bytes}"
+            )));
+        }
+        self.encode_buffer_size = bytes;
+        Ok(())
+    }
+
+    /// Abort the connection, but only after encrypting and writing
+    /// out to `ext.wr` all the plain-text already committed to
+    /// `int.rd`.  This does a final [`TlsServer::process`] call, and then
+    /// discards any remaining data and aborts both `ext.wr` and
+    /// `int.wr` as for [`TlsServer::notify_peer_reset`].  Returns `Ok(true)`
+    /// if all the plain-text was flushed before the abort, or
+    /// `Ok(false)` if some was lost, e.g. because the handshake had
+    /// not yet completed.
+    pub fn abort_after_flush(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<bool, TlsError> {
+        self.process(ext.reborrow(), int.reborrow())?;
+        let flushed = int.rd.is_empty();
+        self.notify_peer_reset(ext, int);
+        Ok(flushed)
+    }
+
+    /// Control whether `ext.wr` is closed once the TLS stream has
+    /// ended cleanly.  When enabled (the default), `ext.wr` is
+    /// closed after the final `close_notify` has been written.  When
+    /// disabled, the `close_notify` is still sent, but `ext.wr` is
+    /// left open so that the caller can manage the transport, e.g.
+    /// for a multiplexed transport that outlives the TLS connection.
+    /// Aborts are still passed through to `ext.wr`.
+    pub fn set_manage_transport_close(&mut self, enabled: bool) {
+        self.close_transport = enabled;
+    }
+
+    /// Check whether application data written to `int.rd` can be sent
+    /// right away, i.e. whether the handshake is complete.  The
+    /// unbuffered wrapper doesn't send 0.5-RTT data.  Always `true`
+    /// if TLS is disabled, and `false` once the outgoing side has
+    /// closed or the transport has failed.
+    pub fn can_send_now(&self) -> bool {
+        !self.write_closed
+            && !self.transport_failed
+            && !self.sc.as_ref().is_some_and(|sc| sc.is_handshaking())
+    }
+
+    /// Deliver each decrypted record's payload to `callback` as a
+    /// separate call, instead of appending it to `int.wr`, for
+    /// protocols that need the record boundaries.  End-of-file is
+    /// still passed to `int.wr` as normal.  (unbuffered only)
+    pub fn set_record_callback(&mut self, callback: impl FnMut(&[u8]) + Send + 'static) {
+        self.on_record = Some(Box::new(callback));
+    }
+
+    /// Check whether the wrapper can be dropped without leaving the
+    /// peer hanging.  This is true if [`TlsServer::process`] has never
+    /// done anything, if the transport has failed, or if both
+    /// directions have ended and `ext` (the `ext.wr` passed to
+    /// [`TlsServer::process`]) has been closed or aborted.
+    pub fn is_safe_to_drop(&self, ext: &PBufWr) -> bool {
+        !self.started
+            || self.transport_failed
+            || (ext.is_eof() && self.read_closed && self.write_closed)
+    }
+
     /// Process as much data as possible, moving data between `ext`
     /// and `int`.  `ext` is the pipe which typically carries TLS
     /// protocol data to/from an external TCP connection.  `int` is
@@ -222,20 +658,115 @@ impl TlsServer {
     ///
     /// Returns `Ok(true)` if there was activity, `Ok(false)` if no
     /// progress could be made, and `Err(_)` if there was an error.
-    pub fn process(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) -> Result<bool, TlsError> {
+    pub fn process(&mut self, ext: PBufRdWr, int: PBufRdWr) -> Result<bool, TlsError> {
+        let result = self.process_inner(ext, int);
+        match result {
+            Ok(activity) => self.started |= activity,
+            Err(ref e) => crate::record_error(&mut self.recent_errors, e),
+        }
+        result
+    }
+
+    /// Make sure that everything waiting to be sent has been written
+    /// to `ext.wr` and pushed, e.g. before blocking on a long
+    /// upstream read, so that the peer isn't left waiting.  The
+    /// unbuffered interface encodes straight into `ext.wr`, so this
+    /// is a [`TlsServer::process`] call followed by a push.  Nothing is
+    /// written if `ext.wr` has been closed.
+    pub fn flush_before_block(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<(), TlsError> {
+        self.process(ext.reborrow(), int.reborrow())?;
+        if !ext.wr.is_eof() {
+            ext.wr.push();
+        }
+        Ok(())
+    }
+
+    /// Process as for [`TlsServer::process`], but retry any recoverable
+    /// failure a bounded number of times before returning an error.
+    /// The only recoverable failure is an outgoing handshake record
+    /// that needs more space than [`TlsServer::set_max_encode_size`]
+    /// allows, which is retried with the limit doubled, up to 3
+    /// times.  Errors from Rustls itself (e.g. a failure to decrypt)
+    /// are always fatal.
+    pub fn process_retry(&mut self, ext: PBufRdWr, int: PBufRdWr) -> Result<bool, TlsError> {
+        self.encode_retries = MAX_ENCODE_RETRIES;
+        let result = self.process(ext, int);
+        self.encode_retries = 0;
+        result
+    }
+
+    // Body of `process`, without the recording of errors
+    fn process_inner(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) -> Result<bool, TlsError> {
         let before = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
 
-        if let Some(ref mut sc) = self.sc {
-            process!(ext, int, sc, true);
+        if self.transport_failed {
+            crate::abort_all(&mut ext, &mut int);
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        } else if let Some(ref mut sc) = self.sc {
+            process!(
+                ext,
+                int,
+                sc,
+                true,
+                self.encode_buffer_size,
+                self.max_encode_size,
+                self.encode_retries,
+                self.close_transport,
+                self.close_notify_sent,
+                self.close_notify_received,
+                self.outbound_termination,
+                self.on_record
+            );
         } else {
             // TLS disabled: Pass data through unchanged
             int.rd.forward(ext.wr.reborrow());
+            if int.rd.is_done() {
+                let how = if int.rd.is_aborted() {
+                    Termination::Abort
+                } else {
+                    Termination::Close
+                };
+                self.outbound_termination.get_or_insert(how);
+            }
             ext.rd.forward(int.wr.reborrow());
         }
 
+        self.read_closed |= int.wr.is_eof();
+        self.write_closed |= int.rd.is_done() || ext.wr.is_eof();
+
         let after = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
         Ok(after != before)
     }
+
+    /// Process as for [`TlsServer::process`], but return a [`Progress`]
+    /// report giving more detail about what happened during the
+    /// call.  For example, this allows a "connection ready" event to
+    /// be fired on exactly the call where the handshake completed.
+    pub fn process_report(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<Progress, TlsError> {
+        let handshaking = self.sc.as_ref().is_some_and(|sc| sc.is_handshaking());
+        let close_notify_sent = self.close_notify_sent;
+        let close_notify_received = self.close_notify_received;
+        let int_done = int.rd.is_done();
+        let ext_done = ext.rd.is_done();
+        let activity = self.process(ext.reborrow(), int.reborrow())?;
+        Ok(Progress {
+            activity,
+            just_completed_handshake: handshaking
+                && !self.sc.as_ref().is_some_and(|sc| sc.is_handshaking()),
+            sent_close_notify: !close_notify_sent && self.close_notify_sent,
+            received_close_notify: !close_notify_received && self.close_notify_received,
+            sent_abort: !int_done && int.rd.is_done() && int.rd.is_aborted(),
+            received_abort: !ext_done && ext.rd.is_done() && ext.rd.is_aborted(),
+        })
+    }
 }
 
 /// [`PipeBuf`] wrapper of [**Rustls**] [`UnbufferedClientConnection`]
@@ -250,6 +781,22 @@ impl TlsServer {
 /// [**Rustls**]: https://crates.io/crates/rustls
 pub struct TlsClient {
     cc: Option<UnbufferedClientConnection>,
+    unstarted_config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+    transport_failed: bool,
+    encode_buffer_size: usize,
+    max_encode_size: usize,
+    encode_retries: u32,
+    close_transport: bool,
+    close_notify_sent: bool,
+    close_notify_received: bool,
+    recent_errors: Vec<TlsError>,
+    outbound_termination: Option<Termination>,
+    close_reason: Option<CloseReason>,
+    started: bool,
+    read_closed: bool,
+    write_closed: bool,
+    allowed_versions: Option<Vec<ProtocolVersion>>,
+    on_record: Option<Box<dyn FnMut(&[u8]) + Send>>,
 }
 
 impl TlsClient {
@@ -259,13 +806,204 @@ impl TlsClient {
     pub fn new(
         config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
     ) -> Result<Self, rustls::Error> {
-        let cc = if let Some((conf, name)) = config {
+        let cc = if let Some((conf, name)) = config.clone() {
             Some(UnbufferedClientConnection::new(conf, name)?)
         } else {
             None
         };
 
-        Ok(Self { cc })
+        Ok(Self {
+            cc,
+            unstarted_config: config,
+            transport_failed: false,
+            encode_buffer_size: DEFAULT_ENCODE_BUFFER_SIZE,
+            max_encode_size: DEFAULT_MAX_ENCODE_SIZE,
+            encode_retries: 0,
+            close_transport: true,
+            close_notify_sent: false,
+            close_notify_received: false,
+            recent_errors: Vec::new(),
+            outbound_termination: None,
+            close_reason: None,
+            started: false,
+            read_closed: false,
+            write_closed: false,
+            allowed_versions: None,
+            on_record: None,
+        })
+    }
+
+    /// Switch a wrapper created without a configuration over to TLS,
+    /// STARTTLS-style.  Calls to [`TlsClient::process`] after this perform
+    /// the TLS handshake and then encrypt and decrypt data as normal.
+    /// Passthrough mode holds no data internally, so nothing is lost
+    /// at the switch-over, but the caller must make sure that the
+    /// plain-text protocol exchange has completed before switching,
+    /// i.e. that no more plain-text is expected from the peer.
+    ///
+    /// Fails if TLS is already active, or if Rustls rejects the
+    /// configuration.
+    pub fn activate_tls(
+        &mut self,
+        config: (Arc<ClientConfig>, ServerName<'static>),
+    ) -> Result<(), rustls::Error> {
+        if self.cc.is_some() {
+            return Err(rustls::Error::General("TLS is already active".into()));
+        }
+        let (conf, name) = config.clone();
+        self.cc = Some(UnbufferedClientConnection::new(conf, name)?);
+        self.unstarted_config = Some(config);
+        Ok(())
+    }
+
+    /// Check that a configuration is accepted by Rustls, by doing a
+    /// trial construction of a connection and then discarding it.
+    /// This allows configurations to be checked at startup rather
+    /// than when the first connection is made.
+    pub fn validate_config(
+        config: (Arc<ClientConfig>, ServerName<'static>),
+    ) -> Result<(), rustls::Error> {
+        let (conf, name) = config;
+        UnbufferedClientConnection::new(conf, name).map(|_| ())
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but with Rustls
+    /// getting the current time from `clock`, for example to make
+    /// certificate validity and ticket ages deterministic in tests.
+    /// This clones the configuration to replace its `time_provider`,
+    /// so for many connections it is cheaper to set the
+    /// `time_provider` on a shared configuration instead.
+    pub fn new_with_clock(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        clock: fn() -> UnixTime,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|(conf, name)| {
+            let mut conf = (*conf).clone();
+            conf.time_provider = Arc::new(Clock(clock));
+            (Arc::new(conf), name)
+        }))
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but only
+    /// accepting the given protocol versions for this connection,
+    /// e.g. to require TLS 1.3 when sharing a configuration with
+    /// connections that allow TLS 1.2.  Rustls doesn't allow the
+    /// versions of an existing configuration to be changed or
+    /// rebuilt, so the ClientHello still offers all the versions that
+    /// the configuration enables.  But as soon as the server picks a
+    /// version that isn't in `versions`, `ext.wr` is aborted and
+    /// [`TlsClient::process`] fails, before any application data is
+    /// exchanged.
+    pub fn new_with_versions(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        versions: &[&'static SupportedProtocolVersion],
+    ) -> Result<Self, rustls::Error> {
+        let mut this = Self::new(config)?;
+        this.allowed_versions = Some(versions.iter().map(|v| v.version).collect());
+        Ok(this)
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but with
+    /// session resumption disabled, so that every connection does a
+    /// full handshake, e.g. for high-security clients.  This clones
+    /// the configuration to replace its `resumption` setting, so for
+    /// many connections it is cheaper to set
+    /// `Resumption::disabled()` on a shared configuration instead.
+    pub fn new_without_resumption(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|(conf, name)| {
+            let mut conf = (*conf).clone();
+            conf.resumption = Resumption::disabled();
+            (Arc::new(conf), name)
+        }))
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but keeping
+    /// resumption data in `store` instead of the configuration's
+    /// session store, e.g. to share sessions through a distributed
+    /// cache.  This clones the configuration to replace its
+    /// `resumption` setting, so for many connections it is cheaper
+    /// to set `Resumption::store` on a shared configuration instead.
+    pub fn new_with_session_store(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        store: Arc<dyn ClientSessionStore>,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|(conf, name)| {
+            let mut conf = (*conf).clone();
+            conf.resumption = Resumption::store(store);
+            (Arc::new(conf), name)
+        }))
+    }
+
+    /// Create a new TLS engine for connecting to `name`, trusting the
+    /// root certificates from the platform's trust store, and using
+    /// the process-default crypto provider.  Fails if no provider
+    /// has been installed, e.g. with `CryptoProvider::install_default`,
+    /// or if no usable root certificates could be loaded.  Requires
+    /// the `native-roots` cargo feature.
+    #[cfg(feature = "native-roots")]
+    pub fn new_native_roots(name: ServerName<'static>) -> Result<Self, rustls::Error> {
+        Self::new(Some((crate::native_roots_config()?, name)))
+    }
+
+    /// Create a new TLS engine for connecting to `name` with mutual
+    /// TLS, trusting the root certificates in `roots` and presenting
+    /// the certificate chain `client_cert` with its private key
+    /// `client_key` if the server asks for a client certificate.
+    /// This uses the process-default crypto provider.  Fails if no
+    /// provider has been installed, e.g. with
+    /// `CryptoProvider::install_default`, or if Rustls rejects the
+    /// key.
+    pub fn new_mtls(
+        roots: RootCertStore,
+        client_cert: Vec<CertificateDer<'static>>,
+        client_key: PrivateKeyDer<'static>,
+        name: ServerName<'static>,
+    ) -> Result<Self, rustls::Error> {
+        let config = ClientConfig::builder_with_provider(crate::default_provider()?)
+            .with_safe_default_protocol_versions()?
+            .with_root_certificates(roots)
+            .with_client_auth_cert(client_cert, client_key)?;
+        Self::new(Some((Arc::new(config), name)))
+    }
+
+    /// Create a new TLS engine as for [`TlsClient::new`], but offering
+    /// the given ALPN protocols for this connection instead of the
+    /// configuration's `alpn_protocols` list.  This clones the
+    /// configuration to make the change.
+    pub fn new_with_alpn_protocols(
+        config: Option<(Arc<ClientConfig>, ServerName<'static>)>,
+        protocols: Vec<Vec<u8>>,
+    ) -> Result<Self, rustls::Error> {
+        Self::new(config.map(|(conf, name)| {
+            let mut conf = (*conf).clone();
+            conf.alpn_protocols = protocols;
+            (Arc::new(conf), name)
+        }))
+    }
+
+    /// Replace the server certificate verifier, for example for
+    /// trust-on-first-use flows.  The connection is rebuilt from a
+    /// copy of the configuration with `verifier` installed.  This is
+    /// only possible before the first call to
+    /// [`TlsClient::process`] after TLS was enabled, since after that
+    /// the handshake has started.
+    /// Fails if TLS is disabled, if the handshake has started, or if
+    /// Rustls rejects the new configuration.
+    pub fn reset_verifier(
+        &mut self,
+        verifier: Arc<dyn ServerCertVerifier>,
+    ) -> Result<(), rustls::Error> {
+        let (conf, name) = self.unstarted_config.clone().ok_or_else(|| {
+            rustls::Error::General("Verifier can only be reset before the handshake starts".into())
+        })?;
+        let mut conf = (*conf).clone();
+        conf.dangerous().set_certificate_verifier(verifier);
+        let conf = Arc::new(conf);
+        self.cc = Some(UnbufferedClientConnection::new(conf.clone(), name.clone())?);
+        self.unstarted_config = Some((conf, name));
+        Ok(())
     }
 
     /// Get immutable access to the wrapped
@@ -274,6 +1012,298 @@ impl TlsClient {
         self.cc.as_ref()
     }
 
+    /// Get mutable access to the wrapped
+    /// `UnbufferedClientConnection`, if available.  Take care, since
+    /// anything done to the connection behind the wrapper's back may
+    /// upset [`TlsClient::process`].
+    pub fn connection_mut(&mut self) -> Option<&mut UnbufferedClientConnection> {
+        self.cc.as_mut()
+    }
+
+    /// Indicate that the transport has failed, e.g. a read or write
+    /// on the underlying socket returned an `io::Error`.  Aborts
+    /// `ext.wr` immediately, and on the next call to
+    /// [`TlsClient::process`] any pending data is discarded and `int.wr`
+    /// is aborted, so that the internal handlers see an "Aborting"
+    /// end-of-file.  The wrapper passes no more data after this call.
+    pub fn transport_error(&mut self, mut ext: PBufRdWr) {
+        self.transport_failed = true;
+        self.close_reason.get_or_insert(CloseReason::TransportError);
+        if !ext.wr.is_eof() {
+            ext.wr.abort();
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        }
+    }
+
+    /// Get the IANA name of the negotiated cipher suite, for example
+    /// `"TLS13_AES_128_GCM_SHA256"`, suitable for use as a metrics
+    /// label.  Returns `None` if TLS is disabled, or if the cipher
+    /// suite has not been negotiated yet.
+    pub fn cipher_suite_name(&self) -> Option<&'static str> {
+        self.cc
+            .as_ref()
+            .and_then(|cc| cc.negotiated_cipher_suite())
+            .and_then(|suite| suite.suite().as_str())
+    }
+
+    /// Get the negotiated protocol version, e.g. for logging.
+    /// Returns `None` if TLS is disabled or the handshake has not
+    /// completed yet.
+    pub fn protocol_version(&self) -> Option<ProtocolVersion> {
+        let cc = self.cc.as_ref().filter(|cc| !cc.is_handshaking())?;
+        cc.protocol_version()
+    }
+
+    /// Get the negotiated cipher suite, e.g. for compliance checks.
+    /// Returns `None` if TLS is disabled or the handshake has not
+    /// completed yet.
+    pub fn negotiated_cipher_suite(&self) -> Option<SupportedCipherSuite> {
+        let cc = self.cc.as_ref().filter(|cc| !cc.is_handshaking())?;
+        cc.negotiated_cipher_suite()
+    }
+
+    /// Check whether the TLS handshake has completed.  Returns
+    /// `false` if TLS is disabled.  To act exactly once on
+    /// completion, see [`TlsClient::process_report`].
+    pub fn handshake_complete(&self) -> bool {
+        self.cc.as_ref().is_some_and(|cc| !cc.is_handshaking())
+    }
+
+    /// Check whether TLS 1.3 was negotiated.  Returns `false` if TLS
+    /// is disabled or the version has not been negotiated yet.
+    pub fn is_tls13(&self) -> bool {
+        self.cc.as_ref().and_then(|cc| cc.protocol_version()) == Some(ProtocolVersion::TLSv1_3)
+    }
+
+    /// Indicate that the transport has seen the peer vanish without a
+    /// clean shutdown, e.g. a connection reset.  Unlike
+    /// [`TlsClient::transport_error`], this takes effect immediately:
+    /// pending data is discarded and both `ext.wr` and `int.wr` are
+    /// aborted, so that the internal handlers see an "Aborting"
+    /// end-of-file straight away.  The wrapper passes no more data
+    /// after this call.
+    pub fn notify_peer_reset(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) {
+        self.transport_failed = true;
+        self.close_reason.get_or_insert(CloseReason::RemoteReset);
+        self.read_closed = true;
+        self.write_closed = true;
+        crate::abort_all(&mut ext, &mut int);
+        self.outbound_termination.get_or_insert(Termination::Abort);
+    }
+
+    /// Check whether the incoming plain-text stream has ended, i.e.
+    /// whether `int.wr` has been closed or aborted
+    pub fn read_closed(&self) -> bool {
+        self.read_closed
+    }
+
+    /// Check whether the outgoing plain-text stream has ended, i.e.
+    /// whether end-of-file from `int.rd` has been handled, or `ext.wr`
+    /// has been closed or aborted.  This together with
+    /// [`TlsClient::read_closed`] makes half-closed states observable.
+    pub fn write_closed(&self) -> bool {
+        self.write_closed
+    }
+
+    /// Check whether the transport can be half-closed, e.g. with
+    /// `shutdown(Write)` on a TCP socket.  This is `true` once our
+    /// `close_notify` has been written to `ext.wr` whilst incoming
+    /// data is still expected, i.e. before the peer has closed its
+    /// side.
+    pub fn wants_transport_half_close(&self) -> bool {
+        self.close_notify_sent && !self.read_closed && !self.close_notify_received
+    }
+
+    /// Get the negotiated ALPN protocol.  Returns `None` if TLS is
+    /// disabled, if the handshake has not got far enough yet, or if
+    /// no protocol was agreed.  The result is only meaningful once
+    /// the handshake is complete, i.e. once `is_handshaking()` on the
+    /// connection returns `false`, so a `None` before then doesn't
+    /// mean that no protocol will be agreed.
+    pub fn alpn_protocol(&self) -> Option<&[u8]> {
+        self.cc.as_ref().and_then(|cc| cc.alpn_protocol())
+    }
+
+    /// Get the name of the negotiated key exchange group, for example
+    /// `"X25519"` or `"X25519MLKEM768"`, suitable for use as a
+    /// metrics label.  Returns `None` if TLS is disabled, or if key
+    /// exchange has not completed yet.
+    pub fn key_exchange_group_str(&self) -> Option<&'static str> {
+        self.cc
+            .as_ref()
+            .and_then(|cc| cc.negotiated_key_exchange_group())
+            .and_then(|group| group.name().as_str())
+    }
+
+    /// Get a summary of the negotiated cryptographic parameters, e.g.
+    /// for a compliance report.  Returns `None` if TLS is disabled or
+    /// the handshake has not completed yet.
+    pub fn crypto_summary(&self) -> Option<CryptoSummary> {
+        let cc = self.cc.as_ref()?;
+        if cc.is_handshaking() {
+            return None;
+        }
+        Some(CryptoSummary {
+            version: cc.protocol_version()?,
+            cipher_suite: cc.negotiated_cipher_suite()?.suite(),
+            kx_group: cc.negotiated_key_exchange_group().map(|group| group.name()),
+        })
+    }
+
+    /// Get the most recent errors returned by [`TlsClient::process`],
+    /// oldest first, for diagnostics when one failure leads to
+    /// others.  Only the last few errors are kept.
+    pub fn recent_errors(&self) -> &[TlsError] {
+        &self.recent_errors
+    }
+
+    /// Check how this wrapper ended the outgoing stream on `ext.wr`,
+    /// e.g. to decide whether to shut down a TCP connection with a
+    /// FIN or to reset it.  Returns `None` if `ext.wr` has not been
+    /// closed or aborted by the wrapper yet.
+    pub fn outbound_termination(&self) -> Option<Termination> {
+        self.outbound_termination
+    }
+
+    /// Get the reason the connection was ended by the transport, if
+    /// [`TlsClient::transport_error`] or [`TlsClient::notify_peer_reset`] has
+    /// been called.  The first reason reported is kept.
+    pub fn close_reason(&self) -> Option<CloseReason> {
+        self.close_reason
+    }
+
+    /// Get the certificate chain presented by the peer, end-entity
+    /// certificate first, e.g. for authorization decisions.  Returns
+    /// `None` if TLS is disabled or if the peer hasn't presented a
+    /// certificate (yet).
+    pub fn peer_certificates(&self) -> Option<&[CertificateDer<'static>]> {
+        self.cc.as_ref()?.peer_certificates()
+    }
+
+    /// Get the end of the validity period ("notAfter") of the
+    /// peer's end-entity certificate, e.g. to monitor for
+    /// certificates that will soon expire.  Returns `None` if TLS is
+    /// disabled, if the peer hasn't presented a certificate (yet),
+    /// or if it can't be parsed.
+    pub fn peer_cert_not_after(&self) -> Option<UnixTime> {
+        let cert = self.cc.as_ref()?.peer_certificates()?.first()?;
+        crate::cert::not_after(cert)
+    }
+
+    /// Get a summary of this wrapper's mode and negotiated
+    /// parameters, for logging or serialization
+    pub fn descriptor(&self) -> ConnectionDescriptor {
+        let cc = self.cc.as_ref();
+        ConnectionDescriptor {
+            server: false,
+            tls: cc.is_some(),
+            protocol_version: cc
+                .and_then(|cc| cc.protocol_version())
+                .and_then(|version| version.as_str())
+                .map(String::from),
+            cipher_suite: self.cipher_suite_name().map(String::from),
+            alpn_protocol: cc.and_then(|cc| cc.alpn_protocol()).map(|p| p.to_vec()),
+        }
+    }
+
+    /// Set the maximum space to allow for encoding an outgoing
+    /// handshake record.  Rustls doesn't say how much space it needs
+    /// up front, so the encode buffer size (or this limit if lower)
+    /// is tried first, and if Rustls asks for more then that is
+    /// allowed up to this limit.  The default is 256KB, which is
+    /// enough for very large certificate chains.
+    pub fn set_max_encode_size(&mut self, bytes: usize) {
+        self.max_encode_size = bytes;
+    }
+
+    /// Set the space first tried for encoding an outgoing handshake
+    /// record, which defaults to 18KB.  A lower value reduces the
+    /// space reserved in `ext.wr` on each call, e.g. for embedded
+    /// use, with a retry if Rustls asks for more, as for
+    /// [`TlsClient::set_max_encode_size`].  Fails if `bytes` is less than
+    /// the size of the largest TLS 1.3 record, 16645 bytes, since
+    /// encoding could then fail for lack of space.
+    pub fn set_encode_buffer_size(&mut self, bytes: usize) -> Result<(), TlsError> {
+        if bytes < MIN_ENCODE_BUFFER_SIZE {
+            return Err(TlsError::Usage(format!(
+                "Encode buffer size must be at least {MIN_ENCODE_BUFFER_SIZE}: {bytes}"
+            )));
+        }
+        self.encode_buffer_size = bytes;
+        Ok(())
+    }
+
+    /// Abort the connection, but only after encrypting and writing
+    /// out to `ext.wr` all the plain-text already committed to
+    /// `int.rd`.  This does a final [`TlsClient::process`] call, and then
+    /// discards any remaining data and aborts both `ext.wr` and
+    /// `int.wr` as for [`TlsClient::notify_peer_reset`].  Returns `Ok(true)`
+    /// if all the plain-text was flushed before the abort, or
+    /// `Ok(false)` if some was lost, e.g. because the handshake had
+    /// not yet completed.
+    pub fn abort_after_flush(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<bool, TlsError> {
+        self.process(ext.reborrow(), int.reborrow())?;
+        let flushed = int.rd.is_empty();
+        self.notify_peer_reset(ext, int);
+        Ok(flushed)
+    }
+
+    /// Get the DNS names listed in the subjectAltName extension of
+    /// the server's end-entity certificate, as accepted during the
+    /// handshake.  The certificate is parsed using `rustls-webpki`,
+    /// the same parser that Rustls uses to verify it.  Returns `None`
+    /// if TLS is disabled, if the server certificate hasn't been
+    /// received yet, or if it can't be parsed.
+    pub fn peer_cert_san(&self) -> Option<Vec<String>> {
+        let cert = self.cc.as_ref()?.peer_certificates()?.first()?;
+        crate::cert_dns_names(cert)
+    }
+
+    /// Control whether `ext.wr` is closed once the TLS stream has
+    /// ended cleanly.  When enabled (the default), `ext.wr` is
+    /// closed after the final `close_notify` has been written.  When
+    /// disabled, the `close_notify` is still sent, but `ext.wr` is
+    /// left open so that the caller can manage the transport, e.g.
+    /// for a multiplexed transport that outlives the TLS connection.
+    /// Aborts are still passed through to `ext.wr`.
+    pub fn set_manage_transport_close(&mut self, enabled: bool) {
+        self.close_transport = enabled;
+    }
+
+    /// Check whether application data written to `int.rd` can be sent
+    /// right away, i.e. whether the handshake is complete.  Early
+    /// data isn't taken from `int.rd`, so it isn't considered here.
+    /// Always `true` if TLS is disabled, and `false` once the
+    /// outgoing side has closed or the transport has failed.
+    pub fn can_send_now(&self) -> bool {
+        !self.write_closed
+            && !self.transport_failed
+            && !self.cc.as_ref().is_some_and(|cc| cc.is_handshaking())
+    }
+
+    /// Deliver each decrypted record's payload to `callback` as a
+    /// separate call, instead of appending it to `int.wr`, for
+    /// protocols that need the record boundaries.  End-of-file is
+    /// still passed to `int.wr` as normal.  (unbuffered only)
+    pub fn set_record_callback(&mut self, callback: impl FnMut(&[u8]) + Send + 'static) {
+        self.on_record = Some(Box::new(callback));
+    }
+
+    /// Check whether the wrapper can be dropped without leaving the
+    /// peer hanging.  This is true if [`TlsClient::process`] has never
+    /// done anything, if the transport has failed, or if both
+    /// directions have ended and `ext` (the `ext.wr` passed to
+    /// [`TlsClient::process`]) has been closed or aborted.
+    pub fn is_safe_to_drop(&self, ext: &PBufWr) -> bool {
+        !self.started
+            || self.transport_failed
+            || (ext.is_eof() && self.read_closed && self.write_closed)
+    }
+
     /// Process as much data as possible, moving data between `ext`
     /// and `int`.  `ext` is the pipe which typically carries TLS
     /// protocol data to/from an external TCP connection.  `int` is
@@ -296,18 +1326,137 @@ impl TlsClient {
     ///
     /// Returns `Ok(true)` if there was activity, `Ok(false)` if no
     /// progress could be made, and `Err(_)` if there was an error.
-    pub fn process(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) -> Result<bool, TlsError> {
+    pub fn process(&mut self, ext: PBufRdWr, int: PBufRdWr) -> Result<bool, TlsError> {
+        if self.cc.is_some() {
+            self.unstarted_config = None;
+        }
+        let result = self.process_inner(ext, int);
+        match result {
+            Ok(activity) => self.started |= activity,
+            Err(ref e) => crate::record_error(&mut self.recent_errors, e),
+        }
+        result
+    }
+
+    /// Make sure that everything waiting to be sent has been written
+    /// to `ext.wr` and pushed, e.g. before blocking on a long
+    /// upstream read, so that the peer isn't left waiting.  The
+    /// unbuffered interface encodes straight into `ext.wr`, so this
+    /// is a [`TlsClient::process`] call followed by a push.  Nothing is
+    /// written if `ext.wr` has been closed.
+    pub fn flush_before_block(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<(), TlsError> {
+        self.process(ext.reborrow(), int.reborrow())?;
+        if !ext.wr.is_eof() {
+            ext.wr.push();
+        }
+        Ok(())
+    }
+
+    /// Process as for [`TlsClient::process`], but retry any recoverable
+    /// failure a bounded number of times before returning an error.
+    /// The only recoverable failure is an outgoing handshake record
+    /// that needs more space than [`TlsClient::set_max_encode_size`]
+    /// allows, which is retried with the limit doubled, up to 3
+    /// times.  Errors from Rustls itself (e.g. a failure to decrypt)
+    /// are always fatal.
+    pub fn process_retry(&mut self, ext: PBufRdWr, int: PBufRdWr) -> Result<bool, TlsError> {
+        self.encode_retries = MAX_ENCODE_RETRIES;
+        let result = self.process(ext, int);
+        self.encode_retries = 0;
+        result
+    }
+
+    // Body of `process`, without the recording of errors
+    fn process_inner(&mut self, mut ext: PBufRdWr, mut int: PBufRdWr) -> Result<bool, TlsError> {
         let before = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
 
-        if let Some(ref mut cc) = self.cc {
-            process!(ext, int, cc, false);
+        if self.transport_failed {
+            crate::abort_all(&mut ext, &mut int);
+            self.outbound_termination.get_or_insert(Termination::Abort);
+        } else if let Some(ref mut cc) = self.cc {
+            process!(
+                ext,
+                int,
+                cc,
+                false,
+                self.encode_buffer_size,
+                self.max_encode_size,
+                self.encode_retries,
+                self.close_transport,
+                self.close_notify_sent,
+                self.close_notify_received,
+                self.outbound_termination,
+                self.on_record
+            );
+            if let Err(e) = crate::check_version(&self.allowed_versions, cc.protocol_version()) {
+                if !ext.wr.is_eof() {
+                    ext.wr.abort();
+                    self.outbound_termination.get_or_insert(Termination::Abort);
+                }
+                return Err(e);
+            }
         } else {
             // TLS disabled: Pass data through unchanged
             int.rd.forward(ext.wr.reborrow());
+            if int.rd.is_done() {
+                let how = if int.rd.is_aborted() {
+                    Termination::Abort
+                } else {
+                    Termination::Close
+                };
+                self.outbound_termination.get_or_insert(how);
+            }
             ext.rd.forward(int.wr.reborrow());
         }
 
+        self.read_closed |= int.wr.is_eof();
+        self.write_closed |= int.rd.is_done() || ext.wr.is_eof();
+
         let after = tripwire!(ext.rd, ext.wr, int.rd, int.wr);
         Ok(after != before)
     }
+
+    /// Do a whole request/response exchange over a blocking
+    /// `transport`, for the simplest clients: complete the handshake,
+    /// send `request`, close the outgoing stream, and then return
+    /// everything received until the server closes its side.
+    /// Requires the `sync` cargo feature.
+    #[cfg(feature = "sync")]
+    pub fn request(
+        &mut self,
+        transport: &mut (impl std::io::Read + std::io::Write),
+        request: &[u8],
+    ) -> Result<Vec<u8>, TlsError> {
+        crate::sync::exchange(|ext, int| self.process(ext, int), transport, request)
+    }
+
+    /// Process as for [`TlsClient::process`], but return a [`Progress`]
+    /// report giving more detail about what happened during the
+    /// call.  For example, this allows a "connection ready" event to
+    /// be fired on exactly the call where the handshake completed.
+    pub fn process_report(
+        &mut self,
+        mut ext: PBufRdWr,
+        mut int: PBufRdWr,
+    ) -> Result<Progress, TlsError> {
+        let handshaking = self.cc.as_ref().is_some_and(|cc| cc.is_handshaking());
+        let close_notify_sent = self.close_notify_sent;
+        let close_notify_received = self.close_notify_received;
+        let int_done = int.rd.is_done();
+        let ext_done = ext.rd.is_done();
+        let activity = self.process(ext.reborrow(), int.reborrow())?;
+        Ok(Progress {
+            activity,
+            just_completed_handshake: handshaking
+                && !self.cc.as_ref().is_some_and(|cc| cc.is_handshaking()),
+            sent_close_notify: !close_notify_sent && self.close_notify_sent,
+            received_close_notify: !close_notify_received && self.close_notify_received,
+            sent_abort: !int_done && int.rd.is_done() && int.rd.is_aborted(),
+            received_abort: !ext_done && ext.rd.is_done() && ext.rd.is_aborted(),
+        })
+    }
 }
@@ -0,0 +1,214 @@
+//! Certificate verifier wrappers that let the application check the
+//! peer's certificate chain from within the handshake, e.g. for
+//! certificate pinning
+
+use rustls::client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier};
+use rustls::pki_types::{CertificateDer, ServerName, UnixTime};
+use rustls::server::danger::{ClientCertVerified, ClientCertVerifier};
+use rustls::{CertificateError, DigitallySignedStruct, DistinguishedName, Error, SignatureScheme};
+use std::fmt;
+use std::sync::Arc;
+
+type Check = Box<dyn Fn(&[CertificateDer<'_>]) -> bool + Send + Sync>;
+
+/// Run the check on the full chain, end-entity certificate first.
+/// Rustls answers `BadEncoding` with a `bad_certificate` alert, which
+/// is the alert a rejected certificate calls for.  Of the errors
+/// mapping to that alert, it is the only one that doesn't claim
+/// something specific about the certificate, such as the name or an
+/// extension being wrong.
+fn check_chain(
+    check: &Check,
+    end_entity: &CertificateDer<'_>,
+    intermediates: &[CertificateDer<'_>],
+) -> Result<(), Error> {
+    let mut chain = Vec::with_capacity(1 + intermediates.len());
+    chain.push(end_entity.clone());
+    chain.extend_from_slice(intermediates);
+    if check(&chain) {
+        Ok(())
+    } else {
+        Err(Error::InvalidCertificate(CertificateError::BadEncoding))
+    }
+}
+
+/// Wrapper around a [`ServerCertVerifier`] that passes the server's
+/// certificate chain to a callback once the inner verifier has
+/// accepted it.  Install it in the `ClientConfig` with
+/// `config.dangerous().set_certificate_verifier(...)` or with
+/// [`TlsClient::reset_verifier`](crate::TlsClient::reset_verifier).
+///
+/// If the callback returns `false`, Rustls fails the handshake with a
+/// `bad_certificate` alert before sending anything further, and
+/// `process` returns the error.  Note that the error, as returned and
+/// as kept for `recent_errors`, is an invalid certificate with
+/// `CertificateError::BadEncoding`, which is also how Rustls reports a
+/// malformed certificate.  The verifier is shared by all the
+/// connections using the config, so it can't mark the error for any
+/// one connection.  If the two cases need telling apart, have the
+/// callback record its rejections.
+pub struct ServerCertCallback {
+    inner: Arc<dyn ServerCertVerifier>,
+    check: Check,
+}
+
+impl ServerCertCallback {
+    /// Wrap `inner`, calling `check` on each chain it accepts
+    pub fn new(
+        inner: Arc<dyn ServerCertVerifier>,
+        check: impl Fn(&[CertificateDer<'_>]) -> bool + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            inner,
+            check: Box::new(check),
+        })
+    }
+}
+
+impl fmt::Debug for ServerCertCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ServerCertCallback")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ServerCertVerifier for ServerCertCallback {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        server_name: &ServerName<'_>,
+        ocsp_response: &[u8],
+        now: UnixTime,
+    ) -> Result<ServerCertVerified, Error> {
+        let verified = self.inner.verify_server_cert(
+            end_entity,
+            intermediates,
+            server_name,
+            ocsp_response,
+            now,
+        )?;
+        check_chain(&self.check, end_entity, intermediates)?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+
+    fn requires_raw_public_keys(&self) -> bool {
+        self.inner.requires_raw_public_keys()
+    }
+
+    fn root_hint_subjects(&self) -> Option<&[DistinguishedName]> {
+        self.inner.root_hint_subjects()
+    }
+}
+
+/// Wrapper around a [`ClientCertVerifier`] that passes the client's
+/// certificate chain to a callback once the inner verifier has
+/// accepted it.  Install it in the `ServerConfig` with
+/// `ServerConfig::builder().with_client_cert_verifier(...)`.
+///
+/// If the callback returns `false`, Rustls fails the handshake with a
+/// `bad_certificate` alert and `process` returns the error.  As for
+/// [`ServerCertCallback`], the error reports
+/// `CertificateError::BadEncoding`, the same as a malformed
+/// certificate.
+pub struct ClientCertCallback {
+    inner: Arc<dyn ClientCertVerifier>,
+    check: Check,
+}
+
+impl ClientCertCallback {
+    /// Wrap `inner`, calling `check` on each chain it accepts
+    pub fn new(
+        inner: Arc<dyn ClientCertVerifier>,
+        check: impl Fn(&[CertificateDer<'_>]) -> bool + Send + Sync + 'static,
+    ) -> Arc<Self> {
+        Arc::new(Self {
+            inner,
+            check: Box::new(check),
+        })
+    }
+}
+
+impl fmt::Debug for ClientCertCallback {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClientCertCallback")
+            .field("inner", &self.inner)
+            .finish_non_exhaustive()
+    }
+}
+
+impl ClientCertVerifier for ClientCertCallback {
+    fn offer_client_auth(&self) -> bool {
+        self.inner.offer_client_auth()
+    }
+
+    fn client_auth_mandatory(&self) -> bool {
+        self.inner.client_auth_mandatory()
+    }
+
+    fn root_hint_subjects(&self) -> &[DistinguishedName] {
+        self.inner.root_hint_subjects()
+    }
+
+    fn verify_client_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        intermediates: &[CertificateDer<'_>],
+        now: UnixTime,
+    ) -> Result<ClientCertVerified, Error> {
+        let verified = self
+            .inner
+            .verify_client_cert(end_entity, intermediates, now)?;
+        check_chain(&self.check, end_entity, intermediates)?;
+        Ok(verified)
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls12_signature(message, cert, dss)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, Error> {
+        self.inner.verify_tls13_signature(message, cert, dss)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.inner.supported_verify_schemes()
+    }
+
+    fn requires_raw_public_keys(&self) -> bool {
+        self.inner.requires_raw_public_keys()
+    }
+}